@@ -7,11 +7,14 @@ use palette::{self, FromColor};
 fn main_impl<C>(matches: clap::ArgMatches) -> Result<(), Box<dyn error::Error>>
 where
     C: rainbowgif::color::Color,
+    palette::rgb::Rgb: palette::convert::FromColorUnclamped<
+        <C as palette::WithAlpha<rainbowgif::color::ScalarType>>::Color,
+    >,
 {
-    let input_colors = rainbowgif::commandline::get_colors::<palette::Lcha>(&matches)?;
+    let gradient_options = rainbowgif::commandline::get_gradient_options(&matches)?;
 
     let steps = matches.get_one::<u32>("count").unwrap();
-    let colors = rainbowgif::commandline::get_gradient(&matches, input_colors, *steps as usize, 1);
+    let colors = gradient_options.generate::<C>(*steps as usize);
 
     let original_width = matches.get_one::<u32>("width").unwrap().to_owned();
     let increment = original_width / steps;
@@ -112,10 +115,18 @@ fn main() -> Result<(), Box<dyn error::Error>> {
             palette::Lcha<rainbowgif::color::WhitePoint, rainbowgif::color::ScalarType>,
         >(matches),
 
+        rainbowgif::color::ColorSpace::OKLAB => {
+            main_impl::<palette::Oklaba<rainbowgif::color::ScalarType>>(matches)
+        }
+
+        rainbowgif::color::ColorSpace::OKLCH => {
+            main_impl::<palette::Oklcha<rainbowgif::color::ScalarType>>(matches)
+        }
+
         _ => Err(Box::new(
             rainbowgif::commandline::CommandlineError::IncompatibleValue(
                 None,
-                "Only HSL, HSV, LAB, and LCH are supported".to_owned(),
+                "Only HSL, HSV, LAB, LCH, OKLAB, and OKLCH are supported".to_owned(),
             ),
         )),
     }
@@ -1,16 +1,23 @@
-use std::error;
 use std::fs;
 use std::io;
 use std::io::Read;
 use std::path;
 use std::vec;
 
+use crate::Error;
+
 pub type Buffer = io::Cursor<vec::Vec<u8>>;
 
 pub struct Data {
     pub buffer: Buffer,
 }
 
+impl Default for Data {
+    fn default() -> Self {
+        return Self::new();
+    }
+}
+
 impl Data {
     pub fn new() -> Self {
         return Data {
@@ -18,12 +25,12 @@ impl Data {
         };
     }
 
-    pub fn from_path<P: AsRef<path::Path>>(p: P) -> Result<Self, Box<dyn error::Error>> {
+    pub fn from_path<P: AsRef<path::Path>>(p: P) -> Result<Self, Error> {
         let file = fs::File::open(p)?;
         return Self::from_file(file);
     }
 
-    pub fn from_file(mut file: fs::File) -> Result<Self, Box<dyn error::Error>> {
+    pub fn from_file(mut file: fs::File) -> Result<Self, Error> {
         let mut data = Self::new();
 
         file.read_to_end(data.buffer.get_mut())?;
@@ -0,0 +1,761 @@
+use std::cell::{Cell, RefCell};
+use std::cmp;
+use std::io;
+use std::marker::PhantomData;
+use std::vec;
+
+use ::gif as gif_lib;
+
+use super::{
+    composite, Decodable, DecodeError, Encodable, EncodeError, Frame, PendingFrame, RgbaFrame,
+};
+use crate::codec;
+use crate::color;
+use crate::Error;
+
+pub const SIGNATURE: [u8; 8] = [0x89, b'P', b'N', b'G', b'\r', b'\n', 0x1A, b'\n'];
+
+// the text chunk keyword comments go under
+const COMMENT_KEYWORD: &str = "Comment";
+
+pub struct ApngDecoder<R: io::Read, C> {
+    phantom: PhantomData<C>,
+    reader: png::Reader<R>,
+    remaining_frames: usize,
+    // the IDAT image isn't part of the animation when there's no fcTL before it
+    skip_default_image: bool,
+    options: codec::DecoderOptions,
+    report: Option<color::quantize::QuantizeReport>,
+
+    // Frames are held back by one, along with the canvas as it's shown while they are, so that the
+    // previous frame can still be turned into a full canvas if the next one has to clear pixels
+    compositor: Option<composite::Compositor>,
+    held: Option<(RgbaFrame, vec::Vec<u8>)>,
+    full_canvas: bool,
+}
+
+impl<R, C> ApngDecoder<R, C>
+where
+    R: io::Read,
+    C: color::Color,
+{
+    pub fn new(read: R) -> Result<Self, Error> {
+        let reader = read_info(read)?;
+
+        let (remaining_frames, skip_default_image) = match reader.info().animation_control {
+            Some(animation) => (
+                animation.num_frames as usize,
+                reader.info().frame_control.is_none(),
+            ),
+            // a plain PNG is treated as a single frame animation
+            None => (1, false),
+        };
+
+        return Ok(ApngDecoder {
+            phantom: PhantomData,
+            reader,
+            remaining_frames,
+            skip_default_image,
+            options: codec::DecoderOptions::default(),
+            report: None,
+            compositor: None,
+            held: None,
+            full_canvas: false,
+        });
+    }
+
+    pub fn with_options(mut self, options: codec::DecoderOptions) -> Result<Self, Error> {
+        self.options = options;
+        self.check_dimensions()?;
+        return Ok(self);
+    }
+
+    pub fn check_dimensions(&self) -> Result<(), Error> {
+        return codec::check_dimensions(self.raw_dimensions(), self.options.downscale);
+    }
+
+    fn raw_dimensions(&self) -> (u32, u32) {
+        let info = self.reader.info();
+        return (info.width, info.height);
+    }
+
+    // the whole canvas as a frame of its own, cleared again once the next frame is due
+    fn canvas_frame(&self, frame: &RgbaFrame, canvas: vec::Vec<u8>) -> RgbaFrame {
+        return RgbaFrame {
+            delay: frame.delay,
+            dispose: gif_lib::DisposalMethod::Background,
+            origin: (0, 0),
+            dimensions: codec::fit_dimensions(self.raw_dimensions()),
+            rgba: canvas,
+            transform: None,
+            quantizer: self.options.quantizer.clone(),
+            transparency: self.options.transparency,
+        };
+    }
+
+    fn read_frame(&mut self) -> Result<Option<(RgbaFrame, png::BlendOp)>, Error> {
+        if self.remaining_frames == 0 {
+            return Ok(None);
+        }
+
+        self.check_dimensions()?;
+        let mut buf = vec![0; self.reader.output_buffer_size()];
+
+        if self.skip_default_image {
+            self.skip_default_image = false;
+            if let Err(e) = self.reader.next_frame(&mut buf) {
+                return Err(Error::Decode(DecodeError::FrameRead(
+                    Some(Box::new(e)),
+                    "Could not read default image".to_owned(),
+                )));
+            }
+        }
+
+        let output_info = match self.reader.next_frame(&mut buf) {
+            Ok(info) => info,
+            Err(e) => {
+                return Err(Error::Decode(DecodeError::FrameRead(
+                    Some(Box::new(e)),
+                    "Could not read frame".to_owned(),
+                )));
+            }
+        };
+        let is_first_frame = self
+            .reader
+            .info()
+            .animation_control
+            .is_none_or(|animation| {
+                return self.remaining_frames == animation.num_frames as usize;
+            });
+        self.remaining_frames -= 1;
+
+        // a plain PNG doesn't have any frame control, so it's the whole image shown indefinitely
+        let frame_control = self
+            .reader
+            .info()
+            .frame_control
+            .unwrap_or(png::FrameControl {
+                width: output_info.width,
+                height: output_info.height,
+                delay_num: 0,
+                ..Default::default()
+            });
+
+        buf.truncate(output_info.line_size * output_info.height as usize);
+        let (rgba, origin, dimensions) = codec::fit_frame(
+            to_rgba(&buf, output_info.color_type)?,
+            (frame_control.x_offset, frame_control.y_offset),
+            (output_info.width, output_info.height),
+            self.raw_dimensions(),
+        );
+
+        // delays are a fraction of a second, where a denominator of 0 means 100
+        let delay_den = if frame_control.delay_den == 0 {
+            100
+        } else {
+            frame_control.delay_den as u32
+        };
+        let delay = ((frame_control.delay_num as u32 * 100) + (delay_den / 2)) / delay_den;
+
+        let dispose = match frame_control.dispose_op {
+            png::DisposeOp::None => gif_lib::DisposalMethod::Keep,
+            png::DisposeOp::Background => gif_lib::DisposalMethod::Background,
+            // there's nothing to go back to for the first frame, so it's cleared instead
+            png::DisposeOp::Previous if is_first_frame => gif_lib::DisposalMethod::Background,
+            png::DisposeOp::Previous => gif_lib::DisposalMethod::Previous,
+        };
+
+        let frame = RgbaFrame {
+            delay: delay.min(u16::MAX as u32) as u16,
+            dispose,
+            origin,
+            dimensions,
+            rgba,
+            transform: None,
+            quantizer: self.options.quantizer.clone(),
+            transparency: self.options.transparency,
+        };
+
+        return Ok(Some((frame, frame_control.blend_op)));
+    }
+}
+
+fn read_info<R: io::Read>(read: R) -> Result<png::Reader<R>, Error> {
+    let mut decoder = png::Decoder::new(read);
+    // always work with 8 bit samples, expanding palettes and tRNS into full color and alpha
+    decoder.set_transformations(png::Transformations::normalize_to_color8());
+
+    return match decoder.read_info() {
+        Ok(reader) => Ok(reader),
+        Err(e) => Err(Error::Decode(DecodeError::Read(
+            Some(Box::new(e)),
+            "Could not read image".to_owned(),
+        ))),
+    };
+}
+
+// Only reads up to the first image data, so it's cheap enough to do before streaming the frames
+pub fn count_frames<R: io::Read>(read: R) -> Result<usize, Error> {
+    let reader = read_info(read)?;
+
+    return Ok(reader
+        .info()
+        .animation_control
+        .map(|animation| animation.num_frames as usize)
+        .unwrap_or(1));
+}
+
+fn to_rgba(buf: &[u8], color_type: png::ColorType) -> Result<vec::Vec<u8>, Error> {
+    return match color_type {
+        png::ColorType::Rgba => Ok(buf.to_vec()),
+        png::ColorType::Rgb => Ok(buf
+            .chunks(3)
+            .flat_map(|chunk| [chunk[0], chunk[1], chunk[2], 255])
+            .collect()),
+        png::ColorType::GrayscaleAlpha => Ok(buf
+            .chunks(2)
+            .flat_map(|chunk| [chunk[0], chunk[0], chunk[0], chunk[1]])
+            .collect()),
+        png::ColorType::Grayscale => Ok(buf.iter().flat_map(|&v| [v, v, v, 255]).collect()),
+        png::ColorType::Indexed => Err(Error::Decode(DecodeError::InvalidData(
+            None,
+            "Indexed PNG data should have been expanded".to_owned(),
+        ))),
+    };
+}
+
+impl<R, C> Decodable for ApngDecoder<R, C>
+where
+    R: io::Read,
+    C: color::Color,
+    palette::rgb::Rgb:
+        palette::convert::FromColorUnclamped<<C as palette::WithAlpha<color::ScalarType>>::Color>,
+{
+    type OutputColor = C;
+
+    fn decode(&mut self) -> Result<Option<Frame<C>>, Error> {
+        let pending = self.decode_pending()?;
+        return codec::finish_pending(pending, &mut self.report);
+    }
+
+    // BlendOp::Over is how GIF frames are drawn already. BlendOp::Source also clears what's under
+    // the transparent pixels of the frame, which a GIF frame can't do, only the disposal of the one
+    // before it can. So once a frame needs that, the frame before it and every one after are
+    // emitted as the whole canvas, each cleared before the next is drawn.
+    fn decode_pending(&mut self) -> Result<Option<PendingFrame<C>>, Error> {
+        loop {
+            let Some((frame, blend)) = self.read_frame()? else {
+                return Ok(self.held.take().map(|(frame, _)| PendingFrame::Rgba(frame)));
+            };
+
+            let clears = blend == png::BlendOp::Source
+                && self.held.is_some()
+                && frame.rgba.chunks(4).any(|pixel| pixel[3] < 255);
+            if clears && !self.full_canvas {
+                self.full_canvas = true;
+                if let Some((held, canvas)) = self.held.take() {
+                    let held = self.canvas_frame(&held, canvas.clone());
+                    self.held = Some((held, canvas));
+                }
+            }
+
+            let dimensions = self.get_dimensions();
+            let compositor = self
+                .compositor
+                .get_or_insert_with(|| composite::Compositor::new(dimensions));
+            let canvas = match blend {
+                png::BlendOp::Source => compositor.replace_rgba(
+                    &frame.rgba,
+                    frame.origin,
+                    frame.dimensions,
+                    frame.dispose,
+                ),
+                png::BlendOp::Over => {
+                    compositor.draw_rgba(&frame.rgba, frame.origin, frame.dimensions, frame.dispose)
+                }
+            }
+            .to_vec();
+
+            let frame = if self.full_canvas {
+                self.canvas_frame(&frame, canvas.clone())
+            } else {
+                frame
+            };
+
+            if let Some((held, _)) = self.held.replace((frame, canvas)) {
+                return Ok(Some(PendingFrame::Rgba(held)));
+            }
+        }
+    }
+
+    fn decode_all(&mut self) -> Result<Option<vec::Vec<Frame<C>>>, Error> {
+        let mut frames = vec::Vec::new();
+
+        while let Some(frame) = self.decode()? {
+            frames.push(frame);
+        }
+
+        if !frames.is_empty() {
+            return Ok(Some(frames));
+        }
+
+        return Ok(None);
+    }
+
+    fn metadata(&self) -> codec::Metadata {
+        let info = self.reader.info();
+
+        // only the text chunks before the image data are there
+        let mut comments = info
+            .uncompressed_latin1_text
+            .iter()
+            .filter(|chunk| chunk.keyword == COMMENT_KEYWORD)
+            .map(|chunk| chunk.text.clone())
+            .collect::<vec::Vec<_>>();
+        comments.extend(
+            info.utf8_text
+                .iter()
+                .filter(|chunk| chunk.keyword == COMMENT_KEYWORD)
+                .filter_map(|chunk| chunk.get_text().ok()),
+        );
+
+        return codec::Metadata {
+            repeat: match info.animation_control {
+                Some(animation) => codec::Metadata::repeat_from_play_count(animation.num_plays),
+                None => gif_lib::Repeat::Infinite,
+            },
+            comments,
+            ..codec::Metadata::default()
+        };
+    }
+
+    fn quantize_report(&self) -> Option<color::quantize::QuantizeReport> {
+        return self.report;
+    }
+
+    fn get_dimensions(&self) -> (u16, u16) {
+        return codec::fit_dimensions(self.raw_dimensions());
+    }
+}
+
+impl<R, C> IntoIterator for ApngDecoder<R, C>
+where
+    R: io::Read,
+    C: color::Color,
+    palette::rgb::Rgb:
+        palette::convert::FromColorUnclamped<<C as palette::WithAlpha<color::ScalarType>>::Color>,
+{
+    type Item = Result<Frame<C>, Error>;
+    type IntoIter = codec::DecoderIter<Self>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        return codec::DecoderIter::new(self);
+    }
+}
+
+pub struct ApngEncoder<W: io::Write, C> {
+    phantom: PhantomData<C>,
+    writer: RefCell<png::Writer<W>>,
+    width: u16,
+    height: u16,
+    frames_written: Cell<usize>,
+}
+
+impl<W, C> ApngEncoder<W, C>
+where
+    W: io::Write,
+    C: color::Color,
+{
+    // APNG needs to know how many frames there are and how often they play before any of them
+    // are written
+    pub fn new(
+        w: W,
+        (width, height): (u16, u16),
+        frame_count: usize,
+        metadata: &codec::Metadata,
+    ) -> Result<Self, Error> {
+        let mut encoder = png::Encoder::new(w, width as u32, height as u32);
+        encoder.set_color(png::ColorType::Rgba);
+        encoder.set_depth(png::BitDepth::Eight);
+
+        // tEXt is Latin-1 only, anything else needs the UTF-8 iTXt
+        for comment in metadata.comments.iter() {
+            let res = if comment.chars().all(|c| (c as u32) < 256) {
+                encoder.add_text_chunk(COMMENT_KEYWORD.to_owned(), comment.clone())
+            } else {
+                encoder.add_itxt_chunk(COMMENT_KEYWORD.to_owned(), comment.clone())
+            };
+
+            if let Err(e) = res {
+                return Err(Error::Encode(EncodeError::Init(
+                    Some(Box::new(e)),
+                    "Could not add comment".to_owned(),
+                )));
+            }
+        }
+
+        let writer = match encoder
+            .set_animated(frame_count as u32, metadata.play_count())
+            .and_then(|_| encoder.write_header())
+        {
+            Ok(writer) => writer,
+            Err(e) => {
+                return Err(Error::Encode(EncodeError::Init(
+                    Some(Box::new(e)),
+                    "Could not write header".to_owned(),
+                )));
+            }
+        };
+
+        return Ok(ApngEncoder {
+            phantom: PhantomData,
+            writer: RefCell::new(writer),
+            width,
+            height,
+            frames_written: Cell::new(0),
+        });
+    }
+
+    pub fn write(&self, frame: Frame<C>) -> Result<(), Error>
+    where
+        palette::rgb::Rgb<color::EncodingType, color::ScalarType>:
+            palette::convert::FromColorUnclamped<
+                <C as palette::WithAlpha<color::ScalarType>>::Color,
+            >,
+    {
+        let canvas = (self.width, self.height);
+        let mut origin = frame.origin;
+        let mut dimensions = frame.dimensions;
+        let mut dispose = match frame.dispose {
+            gif_lib::DisposalMethod::Background => png::DisposeOp::Background,
+            gif_lib::DisposalMethod::Previous => png::DisposeOp::Previous,
+            _ => png::DisposeOp::None,
+        };
+
+        // unlike in a GIF, the first frame has to cover the whole image and the others can't hang
+        // off its edges, so those only keep the part that's on the canvas
+        let (width, height) = (
+            cmp::min(dimensions.0, canvas.0.saturating_sub(origin.0)),
+            cmp::min(dimensions.1, canvas.1.saturating_sub(origin.1)),
+        );
+        let rgba = if self.frames_written.get() == 0 && (origin, dimensions) != ((0, 0), canvas) {
+            let canvas_rgba = composite::Compositor::new(canvas).draw(&frame).to_vec();
+            (origin, dimensions) = ((0, 0), canvas);
+            canvas_rgba
+        } else if width == 0 || height == 0 {
+            // none of it shows, so a single transparent pixel that's left alone keeps its timing
+            (origin, dimensions, dispose) = ((0, 0), (1, 1), png::DisposeOp::None);
+            vec![0u8; 4]
+        } else if (width, height) != dimensions {
+            let rgba = codec::expand_rgba(&frame);
+            let row_len = dimensions.0 as usize * 4;
+            dimensions = (width, height);
+            rgba.chunks(row_len)
+                .take(height as usize)
+                .flat_map(|row| return row[..width as usize * 4].iter().copied())
+                .collect()
+        } else {
+            codec::expand_rgba(&frame)
+        };
+
+        let mut writer = self.writer.borrow_mut();
+        let res = writer
+            .reset_frame_position()
+            .and_then(|_| writer.set_frame_dimension(dimensions.0 as u32, dimensions.1 as u32))
+            .and_then(|_| writer.set_frame_position(origin.0 as u32, origin.1 as u32))
+            .and_then(|_| writer.set_frame_delay(frame.delay, 100))
+            .and_then(|_| writer.set_dispose_op(dispose))
+            // transparent pixels let the previous frame show through, same as in a GIF
+            .and_then(|_| writer.set_blend_op(png::BlendOp::Over))
+            .and_then(|_| writer.write_image_data(&rgba[..]));
+
+        if let Err(e) = res {
+            return Err(Error::Encode(EncodeError::FrameWrite(
+                Some(Box::new(e)),
+                "write_image_data errored".to_owned(),
+            )));
+        }
+
+        self.frames_written.set(self.frames_written.get() + 1);
+
+        return Ok(());
+    }
+
+    pub fn finish(self) -> Result<(), Error> {
+        if let Err(e) = self.writer.into_inner().finish() {
+            return Err(Error::Encode(EncodeError::Write(
+                Some(Box::new(e)),
+                "Could not finish writing".to_owned(),
+            )));
+        }
+
+        return Ok(());
+    }
+}
+
+impl<W, C> Encodable for ApngEncoder<W, C>
+where
+    W: io::Write,
+    C: color::Color,
+    palette::rgb::Rgb<color::EncodingType, color::ScalarType>:
+        palette::convert::FromColorUnclamped<<C as palette::WithAlpha<color::ScalarType>>::Color>,
+{
+    type InputColor = C;
+
+    fn encode(&self, frame: Frame<C>) -> Result<(), Error> {
+        return self.write(frame);
+    }
+
+    fn encode_all(&self, frames: vec::Vec<Frame<C>>) -> Result<(), Error> {
+        for frame in frames {
+            self.write(frame)?;
+        }
+        return Ok(());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ::gif as gif_lib;
+
+    use super::{count_frames, ApngDecoder, ApngEncoder};
+    use crate::codec::{self, composite, gif, Decodable};
+    use crate::color;
+
+    fn encode_apng() -> Vec<u8> {
+        let mut output = Vec::new();
+        {
+            let mut encoder = png::Encoder::new(&mut output, 4, 4);
+            encoder.set_color(png::ColorType::Rgba);
+            encoder.set_depth(png::BitDepth::Eight);
+            encoder.set_animated(3, 0).unwrap();
+
+            let mut writer = encoder.write_header().unwrap();
+            for (i, value) in [0u8, 128, 255].into_iter().enumerate() {
+                writer.set_frame_delay(1, 10 * (i as u16 + 1)).unwrap();
+                writer.set_dispose_op(png::DisposeOp::Previous).unwrap();
+                writer
+                    .write_image_data(&[value, 0, 0, 255].repeat(16))
+                    .unwrap();
+            }
+            writer.finish().unwrap();
+        }
+
+        return output;
+    }
+
+    #[test]
+    fn test_decode_frames() {
+        let data = encode_apng();
+        assert_eq!(count_frames(&data[..]).unwrap(), 3);
+
+        let mut decoder =
+            ApngDecoder::<_, palette::Lcha<color::WhitePoint, color::ScalarType>>::new(&data[..])
+                .unwrap();
+        assert_eq!(decoder.get_dimensions(), (4, 4));
+
+        let frames = decoder.decode_all().unwrap().unwrap();
+        assert_eq!(frames.len(), 3);
+        assert_eq!(
+            frames.iter().map(|f| f.delay).collect::<Vec<_>>(),
+            vec![10, 5, 3]
+        );
+        assert_eq!(frames[0].dispose, gif_lib::DisposalMethod::Background);
+        assert_eq!(frames[1].dispose, gif_lib::DisposalMethod::Previous);
+        assert_eq!(frames[2].pixels_indexed.len(), 16);
+    }
+
+    #[test]
+    fn test_decode_source_blend() {
+        type TestColor = palette::Lcha<color::WhitePoint, color::ScalarType>;
+
+        // an opaque red canvas, a hole replacing its middle, then a pixel drawn over the corner
+        let mut data = Vec::new();
+        {
+            let mut encoder = png::Encoder::new(&mut data, 4, 4);
+            encoder.set_color(png::ColorType::Rgba);
+            encoder.set_depth(png::BitDepth::Eight);
+            encoder.set_animated(3, 0).unwrap();
+
+            let mut writer = encoder.write_header().unwrap();
+            writer.set_dispose_op(png::DisposeOp::None).unwrap();
+            writer.set_blend_op(png::BlendOp::Source).unwrap();
+            writer
+                .write_image_data(&[255, 0, 0, 255].repeat(16))
+                .unwrap();
+
+            writer.set_frame_dimension(2, 2).unwrap();
+            writer.set_frame_position(1, 1).unwrap();
+            writer.write_image_data(&[0; 16]).unwrap();
+
+            writer.set_frame_dimension(1, 1).unwrap();
+            writer.set_frame_position(0, 0).unwrap();
+            writer.set_blend_op(png::BlendOp::Over).unwrap();
+            writer.write_image_data(&[0, 255, 0, 255]).unwrap();
+            writer.finish().unwrap();
+        }
+
+        let frames = ApngDecoder::<_, TestColor>::new(&data[..])
+            .unwrap()
+            .decode_all()
+            .unwrap()
+            .unwrap();
+        assert_eq!(frames.len(), 3);
+
+        let mut compositor = composite::Compositor::new((4, 4));
+        let hole = |x: usize, y: usize| (1..3).contains(&x) && (1..3).contains(&y);
+
+        let shown = compositor.draw(&frames[0]).to_vec();
+        assert!(shown.chunks(4).all(|pixel| pixel[3] == 255));
+
+        for frame in &frames[1..] {
+            let shown = compositor.draw(frame);
+            for (i, pixel) in shown.chunks(4).enumerate() {
+                let (x, y) = (i % 4, i / 4);
+                if hole(x, y) {
+                    assert_eq!(pixel[3], 0, "({}, {})", x, y);
+                } else {
+                    assert_eq!(pixel[3], 255, "({}, {})", x, y);
+                }
+            }
+        }
+        assert!(compositor.canvas()[1] > compositor.canvas()[0]);
+    }
+
+    #[test]
+    fn test_encode_from_gif() {
+        let input = include_bytes!("../../images/fidget_spinner.gif");
+        let mut decoder =
+            gif::GifDecoder::<_, palette::Lcha<color::WhitePoint, color::ScalarType>>::new(
+                &input[..],
+            )
+            .unwrap();
+        let dimensions = decoder.get_dimensions();
+        let frames = decoder.decode_all().unwrap().unwrap();
+        let delays = frames.iter().map(|f| f.delay).collect::<Vec<_>>();
+
+        let mut output = Vec::new();
+        let encoder = ApngEncoder::new(
+            &mut output,
+            dimensions,
+            frames.len(),
+            &codec::Metadata::default(),
+        )
+        .unwrap();
+        for frame in frames {
+            encoder.write(frame).unwrap();
+        }
+        encoder.finish().unwrap();
+
+        let mut decoder =
+            ApngDecoder::<_, palette::Lcha<color::WhitePoint, color::ScalarType>>::new(&output[..])
+                .unwrap();
+        assert_eq!(decoder.get_dimensions(), dimensions);
+
+        let decoded = decoder.decode_all().unwrap().unwrap();
+        assert_eq!(decoded.iter().map(|f| f.delay).collect::<Vec<_>>(), delays);
+    }
+
+    #[test]
+    fn test_encode_off_canvas() {
+        type TestColor = palette::Lcha<color::WhitePoint, color::ScalarType>;
+
+        // GIF frames can hang off the edges of the screen, or be entirely outside of it
+        let frame = |origin: (u16, u16), dimensions: (u16, u16)| {
+            return codec::Frame::<TestColor> {
+                delay: 10,
+                dispose: gif_lib::DisposalMethod::Background,
+                origin,
+                dimensions,
+                palette: codec::Palette::from_gif_format(&[255, 0, 0]),
+                pixels_indexed: vec![0; dimensions.0 as usize * dimensions.1 as usize].into(),
+                transparent_index: None,
+                interlaced: false,
+                needs_input: false,
+            };
+        };
+
+        let mut output = Vec::new();
+        let encoder =
+            ApngEncoder::new(&mut output, (4, 4), 3, &codec::Metadata::default()).unwrap();
+        encoder.write(frame((6, 0), (2, 2))).unwrap();
+        encoder.write(frame((3, 3), (2, 2))).unwrap();
+        encoder.write(frame((0, 9), (4, 4))).unwrap();
+        encoder.finish().unwrap();
+
+        let decoded = ApngDecoder::<_, TestColor>::new(&output[..])
+            .unwrap()
+            .decode_all()
+            .unwrap()
+            .unwrap();
+        assert_eq!(decoded.len(), 3);
+        assert_eq!((decoded[0].origin, decoded[0].dimensions), ((0, 0), (4, 4)));
+        assert!(codec::expand_rgba(&decoded[0])
+            .chunks(4)
+            .all(|pixel| pixel[3] == 0));
+        assert_eq!((decoded[1].origin, decoded[1].dimensions), ((3, 3), (1, 1)));
+        assert_eq!(decoded[2].dimensions, (1, 1));
+    }
+
+    #[test]
+    fn test_transparency() {
+        type TestColor = palette::Lcha<color::WhitePoint, color::ScalarType>;
+
+        // a white square fading out from left to right
+        let input = codec::encode_test_image(
+            image::RgbaImage::from_fn(16, 16, |x, _| image::Rgba([255, 255, 255, (x * 17) as u8])),
+            image::ImageFormat::Png,
+        );
+        let decode = |transparency| {
+            let options = codec::DecoderOptions {
+                transparency,
+                ..codec::DecoderOptions::default()
+            };
+            return ApngDecoder::<_, TestColor>::new(&input[..])
+                .unwrap()
+                .with_options(options)
+                .unwrap()
+                .decode()
+                .unwrap()
+                .unwrap();
+        };
+        let alphas = |rgba: &[u8]| {
+            return rgba[..16 * 4]
+                .chunks(4)
+                .map(|pixel| pixel[3])
+                .collect::<Vec<_>>();
+        };
+
+        // partial alpha survives decoding and encoding again
+        let frame = decode(color::transparency::Transparency {
+            preserve_alpha: true,
+            ..color::transparency::Transparency::default()
+        });
+        let faded = (0..16).map(|x| x * 17).collect::<Vec<_>>();
+        assert_eq!(alphas(&codec::expand_rgba(&frame)), faded);
+
+        let mut output = Vec::new();
+        let encoder =
+            ApngEncoder::new(&mut output, (16, 16), 1, &codec::Metadata::default()).unwrap();
+        encoder.write(frame).unwrap();
+        encoder.finish().unwrap();
+        let rgba = image::load_from_memory(&output[..]).unwrap().to_rgba8();
+        assert_eq!(alphas(&rgba), faded);
+
+        // otherwise it's flattened, where the matte darkens the pixels that were faded
+        let frame = decode(color::transparency::Transparency {
+            threshold: 128,
+            matte: Some([0, 0, 0]),
+            preserve_alpha: false,
+        });
+        let rgba = codec::expand_rgba(&frame);
+        assert_eq!(
+            alphas(&rgba),
+            (0..16)
+                .map(|x| if x * 17 < 128 { 0 } else { 255 })
+                .collect::<Vec<_>>()
+        );
+        assert!(rgba[8 * 4] < rgba[15 * 4]);
+        assert_eq!(rgba[15 * 4], 255);
+    }
+}
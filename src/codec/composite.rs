@@ -0,0 +1,291 @@
+/* Renders frames onto the full logical screen
+ *
+ * Frames coming out of the decoders can be sub-rectangles of the image that only make sense drawn
+ * on top of what came before them, so anything that needs the actual visible image has to play
+ * them back. Transparent pixels leave the canvas under them untouched, partially transparent ones
+ * are blended over it, and after a frame is shown its disposal method decides what the next frame
+ * is drawn onto:
+ *   Keep/Any: the canvas is left as is
+ *   Background: the frame's rectangle is cleared to transparent, which is what browsers do rather
+ *     than using the background color
+ *   Previous: the canvas goes back to how it was before the frame was drawn
+ */
+
+use std::cmp;
+use std::vec;
+
+use ::gif as gif_lib;
+
+use super::Frame;
+use crate::codec;
+use crate::color;
+
+pub struct Compositor {
+    width: u16,
+    height: u16,
+    canvas: vec::Vec<u8>,
+
+    // disposal of the last drawn frame, applied right before the next one is drawn
+    pending_dispose: Option<(gif_lib::DisposalMethod, (u16, u16), (u16, u16))>,
+    previous: Option<vec::Vec<u8>>,
+}
+
+impl Compositor {
+    pub fn new((width, height): (u16, u16)) -> Self {
+        return Compositor {
+            width,
+            height,
+            canvas: vec![0; width as usize * height as usize * 4],
+            pending_dispose: None,
+            previous: None,
+        };
+    }
+
+    pub fn get_dimensions(&self) -> (u16, u16) {
+        return (self.width, self.height);
+    }
+
+    // RGBA pixels of the whole logical screen, as of the last drawn frame
+    pub fn canvas(&self) -> &[u8] {
+        return &self.canvas[..];
+    }
+
+    // Applies the last frame's disposal, returning the canvas the next frame gets drawn onto
+    pub fn disposed(&mut self) -> &[u8] {
+        self.dispose();
+        return self.canvas();
+    }
+
+    // Draws the frame and returns the canvas as it's shown while the frame is
+    pub fn draw<C>(&mut self, frame: &Frame<C>) -> &[u8]
+    where
+        C: color::Color,
+        palette::rgb::Rgb<color::EncodingType, color::ScalarType>:
+            palette::convert::FromColorUnclamped<
+                <C as palette::WithAlpha<color::ScalarType>>::Color,
+            >,
+    {
+        return self.draw_rgba(
+            &codec::expand_rgba(frame),
+            frame.origin,
+            frame.dimensions,
+            frame.dispose,
+        );
+    }
+
+    // Like draw, for the RGBA pixels of a frame that hasn't been quantized
+    pub fn draw_rgba(
+        &mut self,
+        rgba: &[u8],
+        origin: (u16, u16),
+        dimensions: (u16, u16),
+        dispose: gif_lib::DisposalMethod,
+    ) -> &[u8] {
+        return self.draw_impl(rgba, origin, dimensions, dispose, false);
+    }
+
+    // Like draw_rgba, but the pixels replace the ones under them, transparent ones included, which
+    // is how APNG's BlendOp::Source draws
+    pub fn replace_rgba(
+        &mut self,
+        rgba: &[u8],
+        origin: (u16, u16),
+        dimensions: (u16, u16),
+        dispose: gif_lib::DisposalMethod,
+    ) -> &[u8] {
+        return self.draw_impl(rgba, origin, dimensions, dispose, true);
+    }
+
+    fn draw_impl(
+        &mut self,
+        rgba: &[u8],
+        origin: (u16, u16),
+        dimensions: (u16, u16),
+        dispose: gif_lib::DisposalMethod,
+        replace: bool,
+    ) -> &[u8] {
+        self.dispose();
+
+        if dispose == gif_lib::DisposalMethod::Previous {
+            self.previous = Some(self.canvas.clone());
+        }
+
+        let (x, y) = (origin.0 as usize, origin.1 as usize);
+        let frame_width = dimensions.0 as usize;
+        let (width, height) = self.clip(origin, dimensions);
+
+        for row in 0..height {
+            for column in 0..width {
+                let src = ((row * frame_width) + column) * 4;
+                let pixel = &rgba[src..src + 4];
+                let dst = (((y + row) * self.width as usize) + x + column) * 4;
+                match pixel[3] {
+                    _ if replace => self.canvas[dst..dst + 4].copy_from_slice(pixel),
+                    0 => {}
+                    255 => self.canvas[dst..dst + 4].copy_from_slice(pixel),
+                    _ => over(pixel, &mut self.canvas[dst..dst + 4]),
+                }
+            }
+        }
+
+        self.pending_dispose = Some((dispose, origin, dimensions));
+
+        return self.canvas();
+    }
+
+    fn dispose(&mut self) {
+        let Some((dispose, origin, dimensions)) = self.pending_dispose.take() else {
+            return;
+        };
+
+        match dispose {
+            gif_lib::DisposalMethod::Background => {
+                let (x, y) = (origin.0 as usize, origin.1 as usize);
+                let (width, height) = self.clip(origin, dimensions);
+                for row in 0..height {
+                    let start = (((y + row) * self.width as usize) + x) * 4;
+                    self.canvas[start..start + (width * 4)].fill(0);
+                }
+            }
+
+            gif_lib::DisposalMethod::Previous => {
+                if let Some(previous) = self.previous.take() {
+                    self.canvas = previous;
+                }
+            }
+
+            _ => {}
+        }
+    }
+
+    // the part of a frame's rectangle that's actually on the canvas
+    fn clip(&self, origin: (u16, u16), dimensions: (u16, u16)) -> (usize, usize) {
+        let width = cmp::min(
+            dimensions.0 as usize,
+            (self.width as usize).saturating_sub(origin.0 as usize),
+        );
+        let height = cmp::min(
+            dimensions.1 as usize,
+            (self.height as usize).saturating_sub(origin.1 as usize),
+        );
+
+        return (width, height);
+    }
+}
+
+// blends a partially transparent pixel onto the one below it
+fn over(top: &[u8], bottom: &mut [u8]) {
+    let (top_alpha, bottom_alpha) = (top[3] as u32, bottom[3] as u32);
+    // how much of the bottom pixel still shows through, scaled by 255
+    let below = (bottom_alpha * (255 - top_alpha) + 127) / 255;
+    let alpha = top_alpha + below;
+
+    for i in 0..3 {
+        bottom[i] = (((top[i] as u32 * top_alpha) + (bottom[i] as u32 * below) + (alpha / 2))
+            / alpha) as u8;
+    }
+    bottom[3] = alpha as u8;
+}
+
+#[cfg(test)]
+mod tests {
+    use ::gif as gif_lib;
+    use palette::FromColor;
+
+    use super::Compositor;
+    use crate::codec::{self, Frame, Palette};
+    use crate::color;
+
+    type TestColor = palette::Lcha<color::WhitePoint, color::ScalarType>;
+
+    // a frame filled with a single gray value, where index 1 is transparent
+    fn frame(
+        value: color::ScalarType,
+        origin: (u16, u16),
+        dimensions: (u16, u16),
+        dispose: gif_lib::DisposalMethod,
+    ) -> Frame<TestColor> {
+        return Frame {
+            delay: 0,
+            dispose,
+            origin,
+            dimensions,
+            palette: Palette::new(vec![
+                TestColor::from_color(color::ColorType::new(value, value, value, 1.)),
+                TestColor::from_color(color::ColorType::new(0., 0., 0., 0.)),
+            ]),
+            pixels_indexed: vec![0; dimensions.0 as usize * dimensions.1 as usize].into(),
+            transparent_index: Some(1),
+            interlaced: false,
+            needs_input: false,
+        };
+    }
+
+    // the color the frame's opaque pixels end up as
+    fn fill(frame: &Frame<TestColor>) -> [u8; 4] {
+        return codec::expand_rgba(frame)[0..4].try_into().unwrap();
+    }
+
+    fn pixel(canvas: &[u8], (x, y): (usize, usize)) -> [u8; 4] {
+        let start = ((y * 2) + x) * 4;
+        return canvas[start..start + 4].try_into().unwrap();
+    }
+
+    #[test]
+    fn test_dispose_keep_and_background() {
+        let mut compositor = Compositor::new((2, 2));
+
+        compositor.draw(&frame(
+            1.,
+            (0, 0),
+            (2, 2),
+            gif_lib::DisposalMethod::Background,
+        ));
+        let canvas = compositor.draw(&frame(0., (1, 1), (1, 1), gif_lib::DisposalMethod::Keep));
+        // the first frame was cleared, so only the second one is left
+        assert_eq!(pixel(canvas, (0, 0)), [0, 0, 0, 0]);
+        assert_eq!(pixel(canvas, (1, 1)), [0, 0, 0, 255]);
+
+        let mut transparent = frame(1., (0, 0), (2, 2), gif_lib::DisposalMethod::Keep);
+        transparent.pixels_indexed = vec![0, 1, 1, 1].into();
+        let white = fill(&transparent);
+        let canvas = compositor.draw(&transparent);
+        // the second frame was kept and shows through the transparent pixels
+        assert_eq!(pixel(canvas, (0, 0)), white);
+        assert_eq!(pixel(canvas, (1, 0)), [0, 0, 0, 0]);
+        assert_eq!(pixel(canvas, (1, 1)), [0, 0, 0, 255]);
+    }
+
+    #[test]
+    fn test_dispose_previous() {
+        let mut compositor = Compositor::new((2, 2));
+
+        let first = frame(1., (0, 0), (2, 2), gif_lib::DisposalMethod::Keep);
+        let white = fill(&first);
+        compositor.draw(&first);
+        let canvas = compositor.draw(&frame(
+            0.,
+            (0, 0),
+            (1, 1),
+            gif_lib::DisposalMethod::Previous,
+        ));
+        assert_eq!(pixel(canvas, (0, 0)), [0, 0, 0, 255]);
+
+        // frames hanging off the canvas are clipped
+        let canvas = compositor.draw(&frame(0., (1, 1), (4, 4), gif_lib::DisposalMethod::Keep));
+        assert_eq!(pixel(canvas, (0, 0)), white);
+        assert_eq!(pixel(canvas, (1, 1)), [0, 0, 0, 255]);
+    }
+
+    #[test]
+    fn test_partial_alpha() {
+        let mut compositor = Compositor::new((2, 2));
+        compositor.draw(&frame(0., (0, 0), (2, 2), gif_lib::DisposalMethod::Keep));
+
+        let mut half = frame(1., (0, 0), (2, 2), gif_lib::DisposalMethod::Keep);
+        half.palette.colors[0] = TestColor::from_color(color::ColorType::new(1., 1., 1., 0.5));
+        let canvas = compositor.draw(&half);
+        // half white over black ends up gray, and stays opaque
+        assert_eq!(pixel(canvas, (0, 0)), [128, 128, 128, 255]);
+    }
+}
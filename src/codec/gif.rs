@@ -1,17 +1,19 @@
 use std::cell::RefCell;
-use std::error;
+use std::cmp;
+use std::collections;
 use std::io;
 use std::marker::PhantomData;
+use std::rc;
 use std::vec;
 
-use palette::FromColor;
-
-use super::{Decodable, DecodeError, EncodeError, Frame, Palette};
-use crate::color;
+use super::{composite, DecodeError, EncodeError, Frame, Palette};
+use crate::{codec, color, Error};
 
 pub struct GifDecoder<R: io::Read, C> {
     phantom: PhantomData<C>,
-    decoder: gif::Decoder<R>,
+    decoder: gif::Decoder<MetadataReader<R>>,
+    metadata: rc::Rc<RefCell<MetadataParser>>,
+    verify_palette: bool,
 }
 
 impl<R, C> GifDecoder<R, C>
@@ -19,14 +21,20 @@ where
     R: io::Read,
     C: color::Color,
 {
-    pub fn new(read: R) -> Result<Self, Box<dyn error::Error>> {
+    pub fn new(read: R) -> Result<Self, Error> {
         let mut decoder_options = gif::DecodeOptions::new();
         decoder_options.set_color_output(gif::ColorOutput::Indexed);
 
+        let metadata = rc::Rc::new(RefCell::new(MetadataParser::new()));
+        let read = MetadataReader {
+            inner: read,
+            parser: metadata.clone(),
+        };
+
         let decoder = match decoder_options.read_info(read) {
             Ok(dec) => dec,
             Err(e) => {
-                return Err(Box::new(DecodeError::Read(
+                return Err(Error::Decode(DecodeError::Read(
                     Some(Box::new(e)),
                     "Could not read image".to_owned(),
                 )));
@@ -36,6 +44,8 @@ where
         return Ok(GifDecoder {
             phantom: PhantomData,
             decoder,
+            metadata,
+            verify_palette: false,
         });
     }
 
@@ -57,11 +67,11 @@ where
     palette::rgb::Rgb:
         palette::convert::FromColorUnclamped<<C as palette::WithAlpha<color::ScalarType>>::Color>,
 {
-    type Item = Frame<C>;
-    type IntoIter = GifDecoderImpl<R, C>;
+    type Item = Result<Frame<C>, Error>;
+    type IntoIter = codec::DecoderIter<Self>;
 
     fn into_iter(self) -> Self::IntoIter {
-        return GifDecoderImpl { decoder: self };
+        return codec::DecoderIter::new(self);
     }
 }
 
@@ -74,12 +84,12 @@ where
 {
     type OutputColor = C;
 
-    fn decode(&mut self) -> Result<Option<Frame<C>>, Box<dyn error::Error>> {
+    fn decode(&mut self) -> Result<Option<Frame<C>>, Error> {
         let global_pal = self
             .decoder
             .global_palette()
             .map(|e| e.to_vec())
-            .unwrap_or(vec::Vec::new());
+            .unwrap_or_default();
         let frame = match self.decoder.read_next_frame() {
             Ok(f) => {
                 if let Some(f_result) = f {
@@ -88,44 +98,49 @@ where
                     return Ok(None);
                 }
             }
-            Err(e) => return Err(Box::new(e)),
+            Err(e) => {
+                return Err(Error::Decode(DecodeError::FrameRead(
+                    Some(Box::new(e)),
+                    "Could not read frame".to_owned(),
+                )));
+            }
         };
 
-        let pal = if let Some(pal) = &frame.palette {
-            Palette::<C>::from_gif_format(&pal[..])
-        } else {
-            if global_pal.len() == 0 {
-                return Err(Box::new(DecodeError::InvalidData(
+        let pal = match &frame.palette {
+            Some(pal) => &pal[..],
+            None if global_pal.is_empty() => {
+                return Err(Error::Decode(DecodeError::InvalidData(
                     None,
                     "Frame had no valid global palette to fall back to".to_owned(),
                 )));
             }
-
-            Palette::from_gif_format(&global_pal[..])
+            None => &global_pal[..],
+        };
+        let pal = if self.verify_palette {
+            Palette::<C>::from_gif_format_verified(pal)?
+        } else {
+            Palette::<C>::from_gif_format(pal)
         };
 
-        // We don't need to deal with disposal and such since it'll just be encoded back into a gif
+        // disposal is left to whoever needs the visible image, see composite::Compositor
         return Ok(Some(Frame {
             delay: frame.delay,
             dispose: frame.dispose,
             origin: (frame.left, frame.top),
             dimensions: (frame.width, frame.height),
             palette: (pal),
-            pixels_indexed: frame.buffer.to_vec(),
+            pixels_indexed: frame.buffer[..].into(),
             transparent_index: frame.transparent,
             interlaced: frame.interlaced,
             needs_input: frame.needs_user_input,
         }));
     }
 
-    fn decode_all(&mut self) -> Result<Option<vec::Vec<Frame<C>>>, Box<dyn error::Error>> {
+    fn decode_all(&mut self) -> Result<Option<vec::Vec<Frame<C>>>, Error> {
         let mut frames = vec::Vec::new();
 
         loop {
-            let opt = match self.decode() {
-                Ok(opt) => opt,
-                Err(e) => return Err(e),
-            };
+            let opt = self.decode()?;
 
             if let Some(frame) = opt {
                 frames.push(frame);
@@ -134,73 +149,330 @@ where
             }
         }
 
-        if frames.len() != 0 {
+        if !frames.is_empty() {
             return Ok(Some(frames));
         }
 
         return Ok(None);
     }
 
+    fn metadata(&self) -> codec::Metadata {
+        return self.metadata.borrow().metadata.clone();
+    }
+
+    fn set_verify_palette(&mut self, verify_palette: bool) {
+        self.verify_palette = verify_palette;
+    }
+
     fn get_dimensions(&self) -> (u16, u16) {
         let dec_ref = &self.decoder;
         return (dec_ref.width(), dec_ref.height());
     }
 }
 
-pub struct GifDecoderImpl<R: io::Read, C> {
-    decoder: GifDecoder<R, C>,
+// Walks the frame headers without keeping any pixel data around, so the frame count can be known
+// before streaming the frames through
+pub fn count_frames<R: io::Read>(read: R) -> Result<usize, Error> {
+    let mut decoder_options = gif::DecodeOptions::new();
+    decoder_options.set_color_output(gif::ColorOutput::Indexed);
+
+    let mut decoder = match decoder_options.read_info(read) {
+        Ok(dec) => dec,
+        Err(e) => {
+            return Err(Error::Decode(DecodeError::Read(
+                Some(Box::new(e)),
+                "Could not read image".to_owned(),
+            )));
+        }
+    };
+
+    let mut count = 0usize;
+    loop {
+        match decoder.next_frame_info() {
+            Ok(Some(_)) => count += 1,
+            Ok(None) => break,
+            Err(e) => {
+                return Err(Error::Decode(DecodeError::FrameRead(
+                    Some(Box::new(e)),
+                    format!("Could not read frame {}", count),
+                )));
+            }
+        }
+    }
+
+    return Ok(count);
 }
 
-impl<C, R> Iterator for GifDecoderImpl<R, C>
-where
-    R: io::Read,
-    C: color::Color,
-    palette::rgb::Rgb:
-        palette::convert::FromColorUnclamped<<C as palette::WithAlpha<color::ScalarType>>::Color>,
-{
-    type Item = Frame<C>;
+// Walks the whole input for its metadata without decoding any frames, as comments and the like can
+// come after the frames, where a decoder only gets to them once it's read everything
+pub fn read_metadata(data: &[u8]) -> codec::Metadata {
+    let mut parser = MetadataParser::new();
+    for &byte in data {
+        parser.push(byte);
+    }
+
+    return parser.metadata;
+}
+
+// Passes the bytes the gif decoder reads by a MetadataParser, as the decoder skips over the
+// extensions the metadata is in
+struct MetadataReader<R: io::Read> {
+    inner: R,
+    parser: rc::Rc<RefCell<MetadataParser>>,
+}
 
-    fn next(&mut self) -> Option<Self::Item> {
-        if let Ok(res) = self.decoder.decode() {
-            return res;
+impl<R: io::Read> io::Read for MetadataReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+
+        let mut parser = self.parser.borrow_mut();
+        for &byte in buf[..n].iter() {
+            parser.push(byte);
         }
 
-        return None;
+        return Ok(n);
+    }
+}
+
+// where in the GIF block structure the next byte is, with the number of bytes left in the part
+enum ParseState {
+    Screen,
+    GlobalPalette(usize),
+    Block,
+    ExtensionLabel,
+    ImageDescriptor(usize),
+    LocalPalette(usize),
+    CodeSize,
+    SubBlockSize,
+    SubBlock(usize),
+    Done,
+}
+
+// Walks the blocks a byte at a time, only keeping the ones that make up the metadata
+struct MetadataParser {
+    state: ParseState,
+    // the logical screen descriptor, and then the data of the current extension
+    buffer: vec::Vec<u8>,
+    // the label of the extension being read, None for image data
+    extension: Option<u8>,
+    background_index: usize,
+    global_palette: vec::Vec<u8>,
+    metadata: codec::Metadata,
+}
+
+impl MetadataParser {
+    fn new() -> Self {
+        return MetadataParser {
+            state: ParseState::Screen,
+            buffer: vec::Vec::new(),
+            extension: None,
+            background_index: 0,
+            global_palette: vec::Vec::new(),
+            metadata: codec::Metadata {
+                // without a NETSCAPE extension the animation is only shown once
+                repeat: gif::Repeat::Finite(0),
+                ..codec::Metadata::default()
+            },
+        };
+    }
+
+    fn push(&mut self, byte: u8) {
+        self.state = match self.state {
+            // the 6 byte header and 7 byte logical screen descriptor
+            ParseState::Screen => {
+                self.buffer.push(byte);
+                if self.buffer.len() < 13 {
+                    return;
+                }
+
+                let flags = self.buffer[10];
+                self.background_index = self.buffer[11] as usize;
+                self.metadata.pixel_aspect_ratio = self.buffer[12];
+                self.buffer.clear();
+
+                if flags & 0x80 != 0 {
+                    ParseState::GlobalPalette(3 << ((flags & 0x07) + 1))
+                } else {
+                    ParseState::Block
+                }
+            }
+
+            ParseState::GlobalPalette(left) => {
+                self.global_palette.push(byte);
+                if left > 1 {
+                    ParseState::GlobalPalette(left - 1)
+                } else {
+                    let start = self.background_index * 3;
+                    self.metadata.background_color = self
+                        .global_palette
+                        .get(start..start + 3)
+                        .map(|rgb| [rgb[0], rgb[1], rgb[2]]);
+                    ParseState::Block
+                }
+            }
+
+            ParseState::Block => match gif::Block::from_u8(byte) {
+                Some(gif::Block::Extension) => ParseState::ExtensionLabel,
+                Some(gif::Block::Image) => ParseState::ImageDescriptor(9),
+                _ => ParseState::Done,
+            },
+
+            ParseState::ExtensionLabel => {
+                self.extension = Some(byte);
+                ParseState::SubBlockSize
+            }
+
+            // the last byte has the flags
+            ParseState::ImageDescriptor(left) => {
+                if left > 1 {
+                    ParseState::ImageDescriptor(left - 1)
+                } else if byte & 0x80 != 0 {
+                    ParseState::LocalPalette(3 << ((byte & 0x07) + 1))
+                } else {
+                    ParseState::CodeSize
+                }
+            }
+
+            ParseState::LocalPalette(left) => {
+                if left > 1 {
+                    ParseState::LocalPalette(left - 1)
+                } else {
+                    ParseState::CodeSize
+                }
+            }
+
+            ParseState::CodeSize => {
+                self.extension = None;
+                ParseState::SubBlockSize
+            }
+
+            ParseState::SubBlockSize => {
+                if byte == 0 {
+                    self.finish_block();
+                    ParseState::Block
+                } else {
+                    ParseState::SubBlock(byte as usize)
+                }
+            }
+
+            ParseState::SubBlock(left) => {
+                if self.extension.is_some() {
+                    self.buffer.push(byte);
+                }
+
+                if left > 1 {
+                    ParseState::SubBlock(left - 1)
+                } else {
+                    ParseState::SubBlockSize
+                }
+            }
+
+            ParseState::Done => ParseState::Done,
+        };
+    }
+
+    fn finish_block(&mut self) {
+        let data = std::mem::take(&mut self.buffer);
+        match self.extension.take() {
+            Some(label) if label == gif::Extension::Comment as u8 => {
+                self.metadata
+                    .comments
+                    .push(String::from_utf8_lossy(&data[..]).into_owned());
+            }
+
+            // the application identifier followed by the loop sub-block, where 0 loops forever
+            Some(label) if label == gif::Extension::Application as u8 => {
+                let is_loop = data.starts_with(b"NETSCAPE2.0") || data.starts_with(b"ANIMEXTS1.0");
+                if is_loop && data.len() >= 14 && data[11] == 1 {
+                    self.metadata.repeat = match u16::from_le_bytes([data[12], data[13]]) {
+                        0 => gif::Repeat::Infinite,
+                        count => gif::Repeat::Finite(count),
+                    };
+                }
+            }
+
+            _ => {}
+        }
     }
 }
 
-#[allow(dead_code)]
+// frames are either written as they come in, each with its own local palette, or held back until
+// the end so the colors they have in common can go in a single global palette
+enum Output<W: io::Write> {
+    // nothing has been written yet, so the metadata can still change
+    Pending(W),
+    Streaming(gif::Encoder<ScreenWriter<W>>),
+    Buffered(W, vec::Vec<gif::Frame<'static>>),
+    // only while going from Pending to Streaming
+    Starting,
+}
+
 pub struct GifEncoder<W: io::Write, C> {
     phantom: PhantomData<C>,
-    encoder: RefCell<gif::Encoder<W>>,
+    output: RefCell<Output<W>>,
     width: u16,
     height: u16,
+    metadata: codec::Metadata,
+    transparency: color::transparency::Transparency,
+
+    // tracks what's on screen when optimizing, see optimize_frame
+    compositor: Option<RefCell<composite::Compositor>>,
 }
 
-impl<'a, W, C> GifEncoder<W, C>
+impl<W, C> GifEncoder<W, C>
 where
     W: io::Write,
     C: color::Color,
 {
-    pub fn new(w: W, (width, height): (u16, u16)) -> Result<Self, String> {
-        let encoder_impl = match gif::Encoder::new(w, width, height, &[]) {
-            Ok(enc) => RefCell::new(enc),
-            Err(e) => return Err(e.to_string()),
-        };
-
-        if let Err(e) = encoder_impl.borrow_mut().set_repeat(gif::Repeat::Infinite) {
-            return Err(e.to_string());
-        }
+    pub fn new(w: W, (width, height): (u16, u16)) -> Result<Self, Error> {
+        return Ok(GifEncoder {
+            phantom: PhantomData,
+            output: RefCell::new(Output::Pending(w)),
+            width,
+            height,
+            metadata: codec::Metadata::default(),
+            transparency: color::transparency::Transparency::default(),
+            compositor: None,
+        });
+    }
 
+    // Keeps every frame in memory until into_inner, where frames whose colors fit together share
+    // one global palette and the rest keep their local palettes
+    pub fn with_global_palette(w: W, (width, height): (u16, u16)) -> Result<Self, Error> {
         return Ok(GifEncoder {
             phantom: PhantomData,
-            encoder: encoder_impl,
+            output: RefCell::new(Output::Buffered(w, vec::Vec::new())),
             width,
             height,
+            metadata: codec::Metadata::default(),
+            transparency: color::transparency::Transparency::default(),
+            compositor: None,
         });
     }
 
-    pub fn write(&self, frame: Frame<C>) -> Result<(), Box<dyn error::Error>>
+    // has to be set before the first frame, which is when it gets written
+    pub fn set_metadata(&mut self, metadata: codec::Metadata) {
+        self.metadata = metadata;
+    }
+
+    // how palette colors with partial alpha are flattened, see flatten_frame
+    pub fn set_transparency(&mut self, transparency: color::transparency::Transparency) {
+        self.transparency = transparency;
+    }
+
+    // Only writes what changed from one frame to the next, see optimize_frame
+    pub fn set_optimize(&mut self, optimize: bool) {
+        self.compositor = if optimize {
+            Some(RefCell::new(composite::Compositor::new((
+                self.width,
+                self.height,
+            ))))
+        } else {
+            None
+        };
+    }
+
+    pub fn write(&self, frame: Frame<C>) -> Result<(), Error>
     where
         C: color::Color,
         palette::rgb::Rgb<color::EncodingType, color::ScalarType>:
@@ -208,24 +480,23 @@ where
                 <C as palette::WithAlpha<color::ScalarType>>::Color,
             >,
     {
-        let pal = frame
-            .palette
-            .colors
-            .into_iter()
-            .flat_map(|c| {
-                let temp_color = color::ColorType::from_color(c);
-                return [
-                    (temp_color.color.red * 255.) as u8,
-                    (temp_color.color.green * 255.) as u8,
-                    (temp_color.color.blue * 255.) as u8,
-                ];
-            })
-            .collect::<Vec<_>>();
+        let frame = flatten_frame(frame, &self.transparency);
+        let frame = match &self.compositor {
+            Some(compositor) => {
+                let mut compositor = compositor.borrow_mut();
+                let optimized = optimize_frame(frame.clone(), compositor.disposed(), self.width);
+                compositor.draw(&frame);
+                optimized
+            }
+            None => frame,
+        };
+
+        let pal = frame.palette.into_gif_format();
 
         let mut new_frame = gif::Frame::from_palette_pixels(
             frame.dimensions.0,
             frame.dimensions.1,
-            &&frame.pixels_indexed[..],
+            &frame.pixels_indexed[..],
             &pal[..],
             frame.transparent_index,
         );
@@ -236,21 +507,409 @@ where
         new_frame.interlaced = frame.interlaced;
         new_frame.needs_user_input = frame.needs_input;
 
-        if let Err(e) = self.encoder.borrow_mut().write_frame(&new_frame) {
-            return Err(Box::new(EncodeError::FrameWrite(
+        let mut output = self.output.borrow_mut();
+        *output = match std::mem::replace(&mut *output, Output::Starting) {
+            Output::Pending(w) => {
+                Output::Streaming(start(w, (self.width, self.height), &[], &self.metadata)?)
+            }
+            started => started,
+        };
+
+        match &mut *output {
+            Output::Streaming(encoder) => {
+                if let Err(e) = encoder.write_frame(&new_frame) {
+                    return Err(Error::Encode(EncodeError::FrameWrite(
+                        Some(Box::new(e)),
+                        "write_frame errored".to_owned(),
+                    )));
+                }
+            }
+
+            Output::Buffered(_, frames) => frames.push(new_frame),
+
+            _ => {
+                return Err(Error::Encode(EncodeError::Init(
+                    None,
+                    "The encoder failed to start".to_owned(),
+                )));
+            }
+        }
+
+        return Ok(());
+    }
+
+    pub fn into_inner(self) -> Result<W, Error> {
+        let dimensions = (self.width, self.height);
+        let encoder = match self.output.into_inner() {
+            Output::Pending(w) => start(w, dimensions, &[], &self.metadata)?,
+            Output::Streaming(encoder) => encoder,
+            Output::Buffered(w, mut frames) => {
+                let global_palette = share_palette(&mut frames[..]);
+                let mut encoder = start(w, dimensions, &global_palette[..], &self.metadata)?;
+                for frame in frames.iter() {
+                    if let Err(e) = encoder.write_frame(frame) {
+                        return Err(Error::Encode(EncodeError::FrameWrite(
+                            Some(Box::new(e)),
+                            "write_frame errored".to_owned(),
+                        )));
+                    }
+                }
+
+                encoder
+            }
+            Output::Starting => {
+                return Err(Error::Encode(EncodeError::Init(
+                    None,
+                    "The encoder failed to start".to_owned(),
+                )));
+            }
+        };
+
+        return Ok(encoder.into_inner()?.inner);
+    }
+}
+
+// GIFs can't store partial alpha, so palette colors that have it are flattened, and every pixel
+// whose color ends up transparent is pointed at the one transparent index
+fn flatten_frame<C>(
+    mut frame: Frame<C>,
+    transparency: &color::transparency::Transparency,
+) -> Frame<C>
+where
+    C: color::Color,
+    palette::rgb::Rgb<color::EncodingType, color::ScalarType>:
+        palette::convert::FromColorUnclamped<<C as palette::WithAlpha<color::ScalarType>>::Color>,
+{
+    let colors = frame
+        .palette
+        .colors
+        .iter()
+        .map(codec::to_rgba8)
+        .collect::<vec::Vec<_>>();
+    let is_flat = colors
+        .iter()
+        .enumerate()
+        .all(|(i, color)| color[3] == 255 || Some(i as u8) == frame.transparent_index);
+    if is_flat {
+        return frame;
+    }
+
+    let flattened = colors
+        .iter()
+        .map(|&color| transparency.flatten(color))
+        .collect::<vec::Vec<_>>();
+
+    let transparent_index = frame.transparent_index.or_else(|| {
+        return flattened
+            .iter()
+            .position(|color| color[3] == 0)
+            .map(|i| i as u8);
+    });
+    if let Some(transparent_index) = transparent_index {
+        frame.pixels_indexed = frame
+            .pixels_indexed
+            .iter()
+            .map(|&index| match flattened.get(index as usize) {
+                Some(color) if color[3] == 0 => transparent_index,
+                _ => index,
+            })
+            .collect();
+    }
+    frame.transparent_index = transparent_index;
+
+    for (color, (&original, &flattened)) in frame
+        .palette
+        .colors
+        .iter_mut()
+        .zip(colors.iter().zip(flattened.iter()))
+    {
+        if original[3] != 255 && flattened[3] == 255 {
+            *color = codec::from_rgba8(flattened);
+        }
+    }
+
+    return frame;
+}
+
+/* Gets rid of everything in a frame that wouldn't change what's on screen, like gifsicle's -O2
+ *
+ * Pixels that are the same color as the canvas under them are turned into the transparent index,
+ * which compresses a lot better, and the frame is cropped to the pixels left. Frames without a
+ * transparent index get one if there's room in the palette. The disposal stays the same, since
+ * restoring or keeping only the cropped rectangle ends up the same, but frames cleared to the
+ * background aren't cropped as they'd clear less.
+ */
+fn optimize_frame<C>(mut frame: Frame<C>, below: &[u8], canvas_width: u16) -> Frame<C>
+where
+    C: color::Color,
+    palette::rgb::Rgb<color::EncodingType, color::ScalarType>:
+        palette::convert::FromColorUnclamped<<C as palette::WithAlpha<color::ScalarType>>::Color>,
+{
+    let rgba = codec::expand_rgba(&frame);
+    let (width, height) = (frame.dimensions.0 as usize, frame.dimensions.1 as usize);
+    let canvas_width = canvas_width as usize;
+    let canvas_height = below.len() / (canvas_width * 4).max(1);
+
+    // the changed pixels and the bounding box around them, relative to the frame
+    let mut changed = vec![false; width * height];
+    let mut bounds: Option<(usize, usize, usize, usize)> = None;
+    for row in 0..height {
+        for column in 0..width {
+            let i = (row * width) + column;
+            if Some(frame.pixels_indexed[i]) == frame.transparent_index {
+                continue;
+            }
+
+            // pixels hanging off the canvas are never seen
+            let (x, y) = (
+                frame.origin.0 as usize + column,
+                frame.origin.1 as usize + row,
+            );
+            if x >= canvas_width || y >= canvas_height {
+                continue;
+            }
+
+            let under = &below[((y * canvas_width) + x) * 4..][..4];
+            if under[3] == 255 && under[..3] == rgba[i * 4..(i * 4) + 3] {
+                continue;
+            }
+
+            changed[i] = true;
+            bounds = Some(match bounds {
+                Some((left, top, right, bottom)) => (
+                    cmp::min(left, column),
+                    cmp::min(top, row),
+                    cmp::max(right, column),
+                    cmp::max(bottom, row),
+                ),
+                None => (column, row, column, row),
+            });
+        }
+    }
+
+    if frame.transparent_index.is_none() && frame.palette.colors.len() < 256 {
+        frame
+            .palette
+            .colors
+            .push(C::from_color(color::ColorType::new(0., 0., 0., 0.)));
+        frame.transparent_index = Some((frame.palette.colors.len() - 1) as u8);
+    }
+
+    // without room for a transparent index, unchanged pixels are left as the same color
+    if let Some(transparent_index) = frame.transparent_index {
+        let mut pixels = frame.pixels_indexed.to_vec();
+        for (pixel, &changed) in pixels.iter_mut().zip(changed.iter()) {
+            if !changed {
+                *pixel = transparent_index;
+            }
+        }
+        frame.pixels_indexed = pixels.into();
+    }
+
+    if frame.dispose == gif::DisposalMethod::Background || width == 0 || height == 0 {
+        return frame;
+    }
+
+    // a frame that doesn't change anything still has to be there for its delay
+    let (left, top, right, bottom) = bounds.unwrap_or((0, 0, 0, 0));
+    frame.pixels_indexed = (top..=bottom)
+        .flat_map(|row| {
+            return frame.pixels_indexed[(row * width) + left..=(row * width) + right].to_vec();
+        })
+        .collect();
+    frame.origin = (frame.origin.0 + left as u16, frame.origin.1 + top as u16);
+    frame.dimensions = ((right - left + 1) as u16, (bottom - top + 1) as u16);
+
+    return frame;
+}
+
+fn start<W: io::Write>(
+    w: W,
+    (width, height): (u16, u16),
+    global_palette: &[u8],
+    metadata: &codec::Metadata,
+) -> Result<gif::Encoder<ScreenWriter<W>>, Error> {
+    let background_index = metadata
+        .background_color
+        .and_then(|background| {
+            return global_palette
+                .chunks(3)
+                .position(|rgb| rgb == &background[..]);
+        })
+        .unwrap_or(0);
+
+    let w = ScreenWriter {
+        inner: w,
+        position: 0,
+        background_index: background_index as u8,
+        pixel_aspect_ratio: metadata.pixel_aspect_ratio,
+    };
+
+    let mut encoder = match gif::Encoder::new(w, width, height, global_palette) {
+        Ok(enc) => enc,
+        Err(e) => {
+            return Err(Error::Encode(EncodeError::Init(
                 Some(Box::new(e)),
-                "write_frame errored".to_owned(),
+                "Could not write the header".to_owned(),
             )));
         }
+    };
 
-        return Ok(());
+    // a loop count of 0 in the NETSCAPE extension means forever, so playing once is written by
+    // leaving the extension out
+    let written = match metadata.repeat {
+        gif::Repeat::Finite(0) => Ok(()),
+        repeat => encoder.set_repeat(repeat),
+    };
+    if let Err(e) = written {
+        return Err(Error::Encode(EncodeError::Init(
+            Some(Box::new(e)),
+            "Could not write the loop count".to_owned(),
+        )));
+    }
+
+    // right at the start, so decoders that stream the frames see them before any of the frames
+    for comment in metadata.comments.iter() {
+        if let Err(e) =
+            encoder.write_raw_extension(gif::Extension::Comment.into(), &[comment.as_bytes()])
+        {
+            return Err(Error::Encode(EncodeError::Init(
+                Some(Box::new(e)),
+                "Could not write a comment".to_owned(),
+            )));
+        }
+    }
+
+    return Ok(encoder);
+}
+
+// Fills in the background color and pixel aspect ratio of the logical screen descriptor as it
+// goes by, which the gif encoder always leaves at 0
+struct ScreenWriter<W: io::Write> {
+    inner: W,
+    position: usize,
+    background_index: u8,
+    pixel_aspect_ratio: u8,
+}
+
+impl<W: io::Write> io::Write for ScreenWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.position > 12 {
+            return self.inner.write(buf);
+        }
+
+        let mut patched = buf.to_vec();
+        for (i, byte) in patched.iter_mut().enumerate() {
+            match self.position + i {
+                11 => *byte = self.background_index,
+                12 => *byte = self.pixel_aspect_ratio,
+                _ => {}
+            }
+        }
+
+        let n = self.inner.write(&patched[..])?;
+        self.position += n;
+        return Ok(n);
     }
 
-    pub fn into_inner(self) -> Result<W, io::Error> {
-        return self.encoder.into_inner().into_inner();
+    fn flush(&mut self) -> io::Result<()> {
+        return self.inner.flush();
     }
 }
 
+/* Builds the global palette out of the colors the frames actually use, moving every frame whose
+ * colors still fit over to it
+ *
+ * The pixels of a frame are written with as many bits as its palette needs, so a big shared palette
+ * can cost more than the local palettes it saves. Frames with the biggest palettes go first, and a
+ * frame only joins when the shared palette stays within the bits its own palette needs, so none of
+ * them ends up larger. Frames that don't join keep their local palette, so the output looks the
+ * same either way.
+ */
+fn share_palette(frames: &mut [gif::Frame<'static>]) -> vec::Vec<u8> {
+    let mut global: vec::Vec<[u8; 3]> = vec::Vec::new();
+    let mut lookup = collections::HashMap::<[u8; 3], u8>::new();
+    // added the first time a frame with transparency joins
+    let mut transparent_slot: Option<u8> = None;
+
+    let mut order = (0..frames.len()).collect::<vec::Vec<_>>();
+    order.sort_by_key(|&i| {
+        let local_len = frames[i].palette.as_ref().map_or(0, |pal| pal.len() / 3);
+        return cmp::Reverse(code_size(local_len));
+    });
+
+    for i in order {
+        let frame = &mut frames[i];
+        let Some(local) = &frame.palette else {
+            continue;
+        };
+
+        let mut used = [false; 256];
+        for &index in frame.buffer.iter() {
+            used[index as usize] = true;
+        }
+        if let Some(transparent) = frame.transparent {
+            used[transparent as usize] = false;
+        }
+
+        let local_color = |index: usize| -> [u8; 3] {
+            return local
+                .get(index * 3..(index * 3) + 3)
+                .map(|rgb| [rgb[0], rgb[1], rgb[2]])
+                .unwrap_or([0, 0, 0]);
+        };
+
+        let mut new_colors = vec::Vec::new();
+        for index in (0..256).filter(|&index| used[index]) {
+            let color = local_color(index);
+            if !lookup.contains_key(&color) && !new_colors.contains(&color) {
+                new_colors.push(color);
+            }
+        }
+
+        let needs_slot = frame.transparent.is_some() && transparent_slot.is_none();
+        let shared_len = global.len() + new_colors.len() + needs_slot as usize;
+        if shared_len > 256 || code_size(shared_len) > code_size(local.len() / 3) {
+            continue;
+        }
+
+        for color in new_colors {
+            lookup.insert(color, global.len() as u8);
+            global.push(color);
+        }
+
+        // a color of its own, so no opaque pixel ever maps to it
+        if needs_slot {
+            transparent_slot = Some(global.len() as u8);
+            global.push([0, 0, 0]);
+        }
+
+        let mut mapping = [0u8; 256];
+        for index in (0..256).filter(|&index| used[index]) {
+            mapping[index] = lookup[&local_color(index)];
+        }
+        if let Some(transparent) = frame.transparent {
+            mapping[transparent as usize] = transparent_slot.unwrap();
+        }
+
+        let remapped = frame
+            .buffer
+            .iter()
+            .map(|&index| mapping[index as usize])
+            .collect::<vec::Vec<_>>();
+        frame.buffer = remapped.into();
+        frame.transparent = transparent_slot.filter(|_| frame.transparent.is_some());
+        frame.palette = None;
+    }
+
+    return global.concat();
+}
+
+// the bits per pixel the LZW data starts out with, for a palette of the given size
+fn code_size(palette_len: usize) -> u32 {
+    return cmp::max(palette_len.max(1).next_power_of_two().trailing_zeros(), 2);
+}
+
 impl<W, C> super::Encodable for GifEncoder<W, C>
 where
     W: io::Write,
@@ -260,12 +919,12 @@ where
 {
     type InputColor = C;
 
-    fn encode(&self, frame: Frame<C>) -> Result<(), Box<dyn error::Error>> {
+    fn encode(&self, frame: Frame<C>) -> Result<(), Error> {
         // TODO probably should have own error type
         return self.write(frame);
     }
 
-    fn encode_all(&self, frames: vec::Vec<Frame<C>>) -> Result<(), Box<dyn error::Error>> {
+    fn encode_all(&self, frames: vec::Vec<Frame<C>>) -> Result<(), Error> {
         for frame in frames {
             self.write(frame)?;
         }
@@ -286,3 +945,222 @@ where
         return GifEncoder {};
     }
 } */
+
+#[cfg(test)]
+mod tests {
+    use std::vec;
+
+    use palette::FromColor;
+
+    use super::{count_frames, GifDecoder, GifEncoder};
+    use crate::codec::{self, composite, Decodable, Encodable, Frame, Palette};
+    use crate::color;
+
+    const FIDGET_SPINNER: &[u8] = include_bytes!("../../images/fidget_spinner.gif");
+
+    #[test]
+    fn test_count_frames() {
+        let mut decoder =
+            GifDecoder::<_, palette::Lcha<color::WhitePoint, color::ScalarType>>::new(
+                FIDGET_SPINNER,
+            )
+            .unwrap();
+        let frames = decoder.decode_all().unwrap().unwrap();
+
+        assert_eq!(count_frames(FIDGET_SPINNER).unwrap(), frames.len());
+    }
+
+    #[test]
+    fn test_iterator_errors() {
+        let frames = GifDecoder::<_, palette::Lcha<color::WhitePoint, color::ScalarType>>::new(
+            &FIDGET_SPINNER[..FIDGET_SPINNER.len() / 2],
+        )
+        .unwrap()
+        .into_iter()
+        .collect::<vec::Vec<_>>();
+
+        // the truncated frame shows up as an error, and is the last thing the iterator gives
+        assert!(frames.len() > 1);
+        assert!(frames[..frames.len() - 1].iter().all(|frame| frame.is_ok()));
+        assert!(matches!(
+            frames.last(),
+            Some(Err(crate::Error::Decode(codec::DecodeError::FrameRead(..))))
+        ));
+    }
+
+    // whether the frames used the global palette, and their pixels as RGBA
+    fn decode_rgba(data: &[u8]) -> (vec::Vec<bool>, vec::Vec<vec::Vec<u8>>) {
+        let mut options = gif::DecodeOptions::new();
+        options.set_color_output(gif::ColorOutput::RGBA);
+        let mut decoder = options.read_info(data).unwrap();
+
+        let (mut shared, mut pixels) = (vec::Vec::new(), vec::Vec::new());
+        while let Some(frame) = decoder.read_next_frame().unwrap() {
+            shared.push(frame.palette.is_none());
+            pixels.push(frame.buffer.to_vec());
+        }
+
+        return (shared, pixels);
+    }
+
+    #[test]
+    fn test_global_palette() {
+        type TestColor = palette::Lcha<color::WhitePoint, color::ScalarType>;
+        let gray = |value: color::ScalarType, alpha: color::ScalarType| {
+            return TestColor::from_color(color::ColorType::new(value, value, value, alpha));
+        };
+
+        // the last frame's color doesn't fit in the 2 bits the others need
+        let frames = [0., 0.5, 0., 0.5, 0.25]
+            .into_iter()
+            .map(|value| Frame {
+                delay: 0,
+                dispose: gif::DisposalMethod::Keep,
+                origin: (0, 0),
+                dimensions: (2, 2),
+                palette: Palette::new(vec![gray(value, 1.), gray(1., 1.), gray(0., 0.)]),
+                pixels_indexed: vec![0, 1, 2, 0].into(),
+                transparent_index: Some(2),
+                interlaced: false,
+                needs_input: false,
+            })
+            .collect::<vec::Vec<_>>();
+
+        let encode = |global_palette: bool| {
+            let encoder = if global_palette {
+                GifEncoder::with_global_palette(vec::Vec::new(), (2, 2)).unwrap()
+            } else {
+                GifEncoder::new(vec::Vec::new(), (2, 2)).unwrap()
+            };
+            encoder.encode_all(frames.clone()).unwrap();
+            return encoder.into_inner().unwrap();
+        };
+
+        let (local, global) = (encode(false), encode(true));
+        assert!(global.len() < local.len());
+
+        let (local_shared, local_pixels) = decode_rgba(&local[..]);
+        let (global_shared, global_pixels) = decode_rgba(&global[..]);
+        assert!(local_shared.iter().all(|&shared| !shared));
+        assert_eq!(global_shared, vec![true, true, true, true, false]);
+        assert_eq!(local_pixels, global_pixels);
+    }
+
+    #[test]
+    fn test_optimize() {
+        type TestColor = palette::Lcha<color::WhitePoint, color::ScalarType>;
+        let decode = |data: &[u8]| {
+            return GifDecoder::<_, TestColor>::new(data)
+                .unwrap()
+                .decode_all()
+                .unwrap()
+                .unwrap();
+        };
+
+        // the visible image after every frame
+        let render = |data: &[u8]| {
+            let mut compositor = composite::Compositor::new((
+                GifDecoder::<_, TestColor>::new(data).unwrap().get_width(),
+                GifDecoder::<_, TestColor>::new(data).unwrap().get_height(),
+            ));
+            return decode(data)
+                .iter()
+                .map(|frame| compositor.draw(frame).to_vec())
+                .collect::<vec::Vec<_>>();
+        };
+
+        // the same frames twice in a row, so those don't change anything at all, and kept as
+        // frames cleared to the background aren't cropped
+        let doubled = decode(FIDGET_SPINNER)
+            .into_iter()
+            .flat_map(|mut frame| {
+                frame.dispose = gif::DisposalMethod::Keep;
+                return [frame.clone(), frame];
+            })
+            .collect::<vec::Vec<_>>();
+
+        let encode = |optimize: bool| {
+            let decoder = GifDecoder::<_, TestColor>::new(FIDGET_SPINNER).unwrap();
+            let mut encoder =
+                GifEncoder::new(vec::Vec::new(), (decoder.get_width(), decoder.get_height()))
+                    .unwrap();
+            encoder.set_optimize(optimize);
+            encoder.encode_all(doubled.clone()).unwrap();
+            return encoder.into_inner().unwrap();
+        };
+
+        let (plain, optimized) = (encode(false), encode(true));
+        assert!(optimized.len() < plain.len());
+        assert_eq!(render(&plain[..]), render(&optimized[..]));
+
+        let optimized_frames = decode(&optimized[..]);
+        assert_eq!(optimized_frames.len(), doubled.len());
+        assert!(optimized_frames
+            .iter()
+            .skip(1)
+            .step_by(2)
+            .all(|frame| frame.dimensions == (1, 1)));
+    }
+
+    #[test]
+    fn test_metadata_round_trip() {
+        type TestColor = palette::Lcha<color::WhitePoint, color::ScalarType>;
+
+        let decoder = GifDecoder::<_, TestColor>::new(FIDGET_SPINNER).unwrap();
+        assert!(matches!(decoder.metadata().repeat, gif::Repeat::Infinite));
+        let frames = GifDecoder::<_, TestColor>::new(FIDGET_SPINNER)
+            .unwrap()
+            .decode_all()
+            .unwrap()
+            .unwrap();
+
+        let white = codec::expand_rgba(&frames[0])
+            .chunks(4)
+            .find(|pixel| pixel[3] == 255)
+            .map(|pixel| [pixel[0], pixel[1], pixel[2]])
+            .unwrap();
+        let metadata = codec::Metadata {
+            repeat: gif::Repeat::Finite(3),
+            comments: vec!["first".to_owned(), "second ".repeat(50)],
+            background_color: Some(white),
+            pixel_aspect_ratio: 49,
+        };
+
+        let mut encoder = GifEncoder::with_global_palette(
+            vec::Vec::new(),
+            (decoder.get_width(), decoder.get_height()),
+        )
+        .unwrap();
+        encoder.set_metadata(metadata.clone());
+        encoder.encode_all(frames).unwrap();
+        let output = encoder.into_inner().unwrap();
+
+        let mut decoder = GifDecoder::<_, TestColor>::new(&output[..]).unwrap();
+        decoder.decode_all().unwrap();
+        let decoded = decoder.metadata();
+        assert!(matches!(decoded.repeat, gif::Repeat::Finite(3)));
+        assert_eq!(decoded.comments, metadata.comments);
+        assert_eq!(decoded.background_color, metadata.background_color);
+        assert_eq!(decoded.pixel_aspect_ratio, 49);
+
+        // playing once has no loop extension at all, as a loop count of 0 would mean forever
+        let frames = GifDecoder::<_, TestColor>::new(FIDGET_SPINNER)
+            .unwrap()
+            .decode_all()
+            .unwrap()
+            .unwrap();
+        let mut encoder =
+            GifEncoder::new(vec::Vec::new(), (decoder.get_width(), decoder.get_height())).unwrap();
+        encoder.set_metadata(codec::Metadata {
+            repeat: gif::Repeat::Finite(0),
+            ..codec::Metadata::default()
+        });
+        encoder.encode_all(frames).unwrap();
+        let output = encoder.into_inner().unwrap();
+        assert!(!output.windows(11).any(|window| window == b"NETSCAPE2.0"));
+
+        let mut decoder = GifDecoder::<_, TestColor>::new(&output[..]).unwrap();
+        decoder.decode_all().unwrap();
+        assert!(matches!(decoder.metadata().repeat, gif::Repeat::Finite(0)));
+    }
+}
@@ -1,5 +1,3 @@
-use std::collections;
-use std::error;
 use std::io;
 use std::marker;
 use std::vec;
@@ -9,25 +7,26 @@ use image::io::Reader;
 use image::{DynamicImage, ImageFormat};
 use palette;
 
-use super::{Decodable, DecodeError, Frame};
+use super::{Decodable, DecodeError, Frame, PendingFrame, RgbaFrame};
 use crate::codec;
 use crate::color;
+use crate::Error;
 
 pub struct ImageDecoder<C> {
     phantom: marker::PhantomData<C>,
     image: DynamicImage,
     decoded: bool,
+    options: codec::DecoderOptions,
+    report: Option<color::quantize::QuantizeReport>,
 }
 
 impl<C> ImageDecoder<C>
 where
     C: color::Color,
 {
-    fn new_impl<R: io::BufRead + io::Seek>(
-        dec_impl: Reader<R>,
-    ) -> Result<Self, Box<dyn error::Error>> {
+    fn new_impl<R: io::BufRead + io::Seek>(dec_impl: Reader<R>) -> Result<Self, Error> {
         if dec_impl.format().is_none() {
-            return Err(Box::new(DecodeError::Read(
+            return Err(Error::Decode(DecodeError::Read(
                 None,
                 "Passed in Read doesn't yield valid format data".to_owned(),
             )));
@@ -36,10 +35,10 @@ where
         let decoded = match dec_impl.decode() {
             Ok(frame) => frame,
             Err(e) => {
-                return Err(Box::new(DecodeError::Read(
+                return Err(Error::Decode(DecodeError::Read(
                     Some(Box::new(e)),
                     "Unable to decode properly".to_owned(),
-                )))
+                )));
             }
         };
 
@@ -47,13 +46,15 @@ where
             phantom: marker::PhantomData,
             image: decoded,
             decoded: false,
+            options: codec::DecoderOptions::default(),
+            report: None,
         });
     }
 
     pub fn new<R: io::BufRead + io::Seek>(
         read: R,
         format: Option<ImageFormat>,
-    ) -> Result<Self, Box<dyn error::Error>> {
+    ) -> Result<Self, Error> {
         let dec_impl = {
             if let Some(image_format) = format {
                 Reader::with_format(read, image_format)
@@ -63,6 +64,20 @@ where
         };
         return Self::new_impl(dec_impl);
     }
+
+    pub fn with_options(mut self, options: codec::DecoderOptions) -> Result<Self, Error> {
+        self.options = options;
+        self.check_dimensions()?;
+        return Ok(self);
+    }
+
+    pub fn check_dimensions(&self) -> Result<(), Error> {
+        return codec::check_dimensions(self.raw_dimensions(), self.options.downscale);
+    }
+
+    fn raw_dimensions(&self) -> (u32, u32) {
+        return (self.image.width(), self.image.height());
+    }
 }
 
 impl<C> Decodable for ImageDecoder<C>
@@ -73,72 +88,39 @@ where
 {
     type OutputColor = C;
 
-    fn decode(&mut self) -> Result<Option<Frame<Self::OutputColor>>, Box<dyn error::Error>> {
+    fn decode(&mut self) -> Result<Option<Frame<Self::OutputColor>>, Error> {
+        let pending = self.decode_pending()?;
+        return codec::finish_pending(pending, &mut self.report);
+    }
+
+    fn decode_pending(&mut self) -> Result<Option<PendingFrame<Self::OutputColor>>, Error> {
         if self.decoded {
             return Ok(None);
         }
 
-        let mut buf = self.image.to_rgba8();
-        let mut transparent_indices = collections::hash_set::HashSet::new();
-
-        // in a gif if a value isn't fully opaque, it's considered transparent
-        for (i, mut pixel) in buf.pixels_mut().enumerate() {
-            if pixel.0[3] != 255 {
-                transparent_indices.insert(i);
-                pixel.0[0] = 0;
-                pixel.0[1] = 0;
-                pixel.0[2] = 0;
-                pixel.0[3] = 0;
-            }
-        }
-
-        let quantizer =
-            color::quantize::Quantizer::new(256, color::quantize::QuantizerType::IMAGEQUANT);
-        let (pixels, indices) = quantizer.run(
-            buf.into_vec()[..]
-                .chunks(4)
-                .map(|chunk| {
-                    let [r, g, b, a]: [u8; 4] = chunk.try_into().unwrap();
-                    return (r, g, b, a);
-                })
-                .collect(),
-            (self.image.width() as usize, self.image.height() as usize),
-        )?;
-
-        let transparent_index = pixels.iter().position(|&x| x.3 == 0).map(|i| i as u8);
-
-        let quantized_pixels = pixels
-            .into_iter()
-            .map(|pixel| {
-                return C::from_color(color::ColorType::new(
-                    (pixel.0 as color::ScalarType) / 255.,
-                    (pixel.1 as color::ScalarType) / 255.,
-                    (pixel.2 as color::ScalarType) / 255.,
-                    (pixel.3 as color::ScalarType) / 255.,
-                ));
-            })
-            .collect::<vec::Vec<_>>();
-
-        let pal = codec::Palette::new(quantized_pixels);
-
+        self.check_dimensions()?;
+        let (rgba, _, dimensions) = codec::fit_frame(
+            self.image.to_rgba8().into_vec(),
+            (0, 0),
+            self.raw_dimensions(),
+            self.raw_dimensions(),
+        );
         self.decoded = true;
 
-        return Ok(Some(Frame {
-            delay: 0, // TODO #35
+        return Ok(Some(PendingFrame::Rgba(RgbaFrame {
+            // still images have no timing of their own, see pipeline::Timing
+            delay: 0,
             dispose: gif_lib::DisposalMethod::Keep,
             origin: (0, 0),
-            dimensions: self.get_dimensions(),
-            palette: pal,
-            pixels_indexed: indices,
-            transparent_index,
-            interlaced: false,
-            needs_input: false,
-        }));
+            dimensions,
+            rgba,
+            transform: None,
+            quantizer: self.options.quantizer.clone(),
+            transparency: self.options.transparency,
+        })));
     }
 
-    fn decode_all(
-        &mut self,
-    ) -> Result<Option<vec::Vec<Frame<Self::OutputColor>>>, Box<dyn error::Error>> {
+    fn decode_all(&mut self) -> Result<Option<vec::Vec<Frame<Self::OutputColor>>>, Error> {
         if self.decoded {
             return Ok(None);
         }
@@ -150,8 +132,12 @@ where
         return Ok(None);
     }
 
+    fn quantize_report(&self) -> Option<color::quantize::QuantizeReport> {
+        return self.report;
+    }
+
     fn get_dimensions(&self) -> (u16, u16) {
-        return (self.image.width() as u16, self.image.height() as u16);
+        return codec::fit_dimensions(self.raw_dimensions());
     }
 }
 
@@ -161,31 +147,10 @@ where
     palette::rgb::Rgb:
         palette::convert::FromColorUnclamped<<C as palette::WithAlpha<color::ScalarType>>::Color>,
 {
-    type Item = Frame<C>;
-    type IntoIter = ImageDecoderIter<C>;
+    type Item = Result<Frame<C>, Error>;
+    type IntoIter = codec::DecoderIter<Self>;
 
     fn into_iter(self) -> Self::IntoIter {
-        return ImageDecoderIter { decoder: self };
-    }
-}
-
-pub struct ImageDecoderIter<C> {
-    decoder: ImageDecoder<C>,
-}
-
-impl<C> Iterator for ImageDecoderIter<C>
-where
-    C: color::Color,
-    palette::rgb::Rgb:
-        palette::convert::FromColorUnclamped<<C as palette::WithAlpha<color::ScalarType>>::Color>,
-{
-    type Item = Frame<C>;
-
-    fn next(&mut self) -> Option<Self::Item> {
-        if let Ok(res) = self.decoder.decode() {
-            return res;
-        }
-
-        return None;
+        return codec::DecoderIter::new(self);
     }
 }
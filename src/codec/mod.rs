@@ -1,13 +1,21 @@
-use std::error;
+use std::cmp;
+use std::io;
+use std::path;
+use std::sync;
 use std::vec;
 
 use ::gif as gif_lib;
+use clap::{builder::PossibleValue, ValueEnum};
 use palette::FromColor;
 
-use crate::{color, error_utils};
+use crate::{color, commandline, error_utils, Error};
 
+pub mod apng;
+pub mod composite;
 pub mod gif;
 pub mod image;
+pub mod transform;
+pub mod webp;
 
 error_utils::define_error!(DecodeError, {
     Init: "Error initializing decoder",
@@ -22,6 +30,151 @@ error_utils::define_error!(EncodeError, {
     FrameWrite: "Error write frame",
 });
 
+commandline::define_cli_enum!(OutputFormat, {
+    Gif: ("gif", "GIF, limited to 256 colors per frame and fully transparent or opaque pixels"),
+    Apng: ("apng", "Animated PNG, with full RGBA frames"),
+    Webp: ("webp", "Animated lossless WebP, with full RGBA frames"),
+});
+
+impl OutputFormat {
+    // guesses the format from the file extension
+    pub fn from_path<P: AsRef<path::Path>>(p: P) -> Option<Self> {
+        let extension = p.as_ref().extension()?.to_str()?.to_lowercase();
+        return match extension.as_str() {
+            "gif" => Some(Self::Gif),
+            "png" | "apng" => Some(Self::Apng),
+            "webp" => Some(Self::Webp),
+            _ => None,
+        };
+    }
+}
+
+// Input formats told apart by their magic bytes, so the right decoder is picked without being told
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum InputFormat {
+    Gif,
+    // APNG too, where a plain PNG is read as a single frame animation
+    Png,
+    Webp,
+    // anything else the image crate can read (JPEG, BMP, ...), which is always a single frame
+    Still(::image::ImageFormat),
+}
+
+impl InputFormat {
+    pub fn sniff(data: &[u8]) -> Result<Self, Error> {
+        if data.starts_with(b"GIF87a") || data.starts_with(b"GIF89a") {
+            return Ok(Self::Gif);
+        }
+
+        if data.starts_with(&apng::SIGNATURE) {
+            return Ok(Self::Png);
+        }
+
+        if webp::has_signature(data) {
+            return Ok(Self::Webp);
+        }
+
+        return match ::image::guess_format(data) {
+            Ok(format) => Ok(Self::Still(format)),
+            Err(e) => Err(Error::Decode(DecodeError::InvalidData(
+                Some(Box::new(e)),
+                "Unrecognized input format".to_owned(),
+            ))),
+        };
+    }
+
+    // Only walks the headers where possible, so it's cheap enough to do before streaming frames
+    pub fn count_frames(&self, data: &[u8]) -> Result<usize, Error> {
+        return match self {
+            Self::Gif => gif::count_frames(data),
+            Self::Png => apng::count_frames(data),
+            Self::Webp => webp::count_frames(io::Cursor::new(data)),
+            Self::Still(_) => Ok(1),
+        };
+    }
+
+    // Metadata from walking the whole input, for the formats where it can come after the frames and
+    // so isn't known to a decoder that was just opened. Text chunks after a PNG's image data would
+    // need every frame decoded first, so for PNG it's left to the decoder.
+    pub fn read_metadata(&self, data: &[u8]) -> Option<Metadata> {
+        return match self {
+            Self::Gif => Some(gif::read_metadata(data)),
+            Self::Png | Self::Webp | Self::Still(_) => None,
+        };
+    }
+
+    // the options are only used by the decoders of formats that aren't already indexed, and so
+    // might have partial alpha or be larger than a GIF allows
+    pub fn open<'a, C>(
+        &self,
+        data: &'a [u8],
+        options: &DecoderOptions,
+    ) -> Result<Box<dyn Decodable<OutputColor = C> + 'a>, Error>
+    where
+        C: color::Color + 'a,
+        palette::rgb::Rgb: palette::convert::FromColorUnclamped<
+            <C as palette::WithAlpha<color::ScalarType>>::Color,
+        >,
+    {
+        return match self {
+            Self::Gif => Ok(Box::new(gif::GifDecoder::new(data)?)),
+            Self::Png => Ok(Box::new(
+                apng::ApngDecoder::new(data)?.with_options(options.clone())?,
+            )),
+            Self::Webp => Ok(Box::new(
+                webp::WebpDecoder::new(io::Cursor::new(data))?.with_options(options.clone())?,
+            )),
+            Self::Still(format) => Ok(Box::new(
+                image::ImageDecoder::new(io::Cursor::new(data), Some(*format))?
+                    .with_options(options.clone())?,
+            )),
+        };
+    }
+}
+
+// Things about the whole animation rather than a single frame, carried over from the input as far
+// as the formats allow
+#[derive(Clone, Debug)]
+pub struct Metadata {
+    // how many more times the animation plays after the first time
+    pub repeat: gif_lib::Repeat,
+    pub comments: vec::Vec<String>,
+
+    // these only exist in GIFs, and the background is only written when the output has a global
+    // palette to point into
+    pub background_color: Option<[u8; 3]>,
+    // the raw value, where the ratio is (value + 15) / 64 and 0 means there's no information
+    pub pixel_aspect_ratio: u8,
+}
+
+impl Default for Metadata {
+    fn default() -> Self {
+        return Metadata {
+            repeat: gif_lib::Repeat::Infinite,
+            comments: vec::Vec::new(),
+            background_color: None,
+            pixel_aspect_ratio: 0,
+        };
+    }
+}
+
+impl Metadata {
+    // APNG and WebP count how many times the animation is shown in total, where 0 is forever
+    pub fn play_count(&self) -> u32 {
+        return match self.repeat {
+            gif_lib::Repeat::Infinite => 0,
+            gif_lib::Repeat::Finite(count) => count as u32 + 1,
+        };
+    }
+
+    pub fn repeat_from_play_count(play_count: u32) -> gif_lib::Repeat {
+        return match play_count {
+            0 => gif_lib::Repeat::Infinite,
+            count => gif_lib::Repeat::Finite(cmp::min(count - 1, u16::MAX as u32) as u16),
+        };
+    }
+}
+
 // TODO make private after iterable
 #[derive(Clone)]
 pub struct Palette<C> {
@@ -51,34 +204,78 @@ where
                 .chunks(3)
                 .map(|chunk| {
                     let [r, g, b]: [u8; 3] = chunk.try_into().unwrap();
-                    return C::from_color(color::ColorType::new(
-                        r as color::ScalarType / 255.,
-                        g as color::ScalarType / 255.,
-                        b as color::ScalarType / 255.,
-                        1.,
-                    ));
+                    return from_rgba8([r, g, b, 255]);
                 })
                 .collect(),
         };
     }
 
-    #[allow(dead_code)]
+    // Like from_gif_format, but fails if any of the colors doesn't come back out of into_gif_format
+    // as the same bytes, so leaving the palette alone is guaranteed to reproduce the input
+    pub fn from_gif_format_verified(colors: &[u8]) -> Result<Self, Error> {
+        let palette = Self::from_gif_format(colors);
+        for (color, expected) in palette.colors.iter().zip(colors.chunks(3)) {
+            let actual = to_rgba8(color);
+            if actual[..3] != *expected {
+                return Err(Error::Decode(DecodeError::InvalidData(
+                    None,
+                    format!(
+                        "Palette color {:02x?} came back as {:02x?} from the color space",
+                        expected,
+                        &actual[..3]
+                    ),
+                )));
+            }
+        }
+
+        return Ok(palette);
+    }
+
     pub fn into_gif_format(self) -> vec::Vec<u8> {
         return self
             .colors
-            .into_iter()
+            .iter()
             .flat_map(|c| {
-                let c_prime = color::ColorType::from_color(c);
-                return [
-                    (c_prime.red * 255.) as u8,
-                    (c_prime.green * 255.) as u8,
-                    (c_prime.blue * 255.) as u8,
-                ];
+                let [r, g, b, _] = to_rgba8(c);
+                return [r, g, b];
             })
             .collect();
     }
 }
 
+// 8 bit sRGB channels to and from the colors, rounding to the nearest value and clamping whatever
+// mixing pushed out of range, so a color read in and written back out is the same bytes
+pub(crate) fn to_rgba8<C>(color: &C) -> [u8; 4]
+where
+    C: color::Color,
+    palette::rgb::Rgb<color::EncodingType, color::ScalarType>:
+        palette::convert::FromColorUnclamped<<C as palette::WithAlpha<color::ScalarType>>::Color>,
+{
+    let channel = |value: color::ScalarType| {
+        return (value * 255.).round().clamp(0., 255.) as u8;
+    };
+
+    let c = color::ColorType::from_color(color.clone());
+    return [
+        channel(c.color.red),
+        channel(c.color.green),
+        channel(c.color.blue),
+        channel(c.alpha),
+    ];
+}
+
+pub(crate) fn from_rgba8<C>([r, g, b, a]: [u8; 4]) -> C
+where
+    C: color::Color,
+{
+    return C::from_color(color::ColorType::new(
+        r as color::ScalarType / 255.,
+        g as color::ScalarType / 255.,
+        b as color::ScalarType / 255.,
+        a as color::ScalarType / 255.,
+    ));
+}
+
 #[derive(Clone)]
 pub struct Frame<C>
 where
@@ -98,7 +295,8 @@ where
     pub palette: Palette<C>,
 
     // pixels indexing into palette (local if present, otherwise global)
-    pub pixels_indexed: vec::Vec<u8>,
+    // shared between copies of the frame, since looping only gives each copy its own palette
+    pub pixels_indexed: sync::Arc<[u8]>,
 
     // transparent pixel index, if available
     pub transparent_index: Option<u8>,
@@ -108,6 +306,101 @@ where
     pub needs_input: bool,
 }
 
+// A frame as it comes out of a decoder, where formats that aren't indexed still need their pixels
+// quantized. That's by far the slowest part of decoding, so it's left to finish, which doesn't need
+// the decoder anymore and can run on any thread.
+pub enum PendingFrame<C>
+where
+    C: color::Color,
+{
+    Indexed(Frame<C>),
+    Rgba(RgbaFrame),
+}
+
+// the pixels of a frame before quantizing, along with everything needed to quantize them
+pub struct RgbaFrame {
+    pub delay: u16,
+    pub dispose: gif_lib::DisposalMethod,
+    pub origin: (u16, u16),
+    // of the frame once it's transformed, if it still needs to be
+    pub dimensions: (u16, u16),
+    pub rgba: vec::Vec<u8>,
+    // what the pixels still need to go through first, with the size they have until then
+    pub transform: Option<(transform::Transform, (u16, u16))>,
+    pub quantizer: color::quantize::Quantizer,
+    pub transparency: color::transparency::Transparency,
+}
+
+impl RgbaFrame {
+    // the pixels with the transform applied, if there was one
+    pub fn transformed_rgba(self) -> vec::Vec<u8> {
+        return match self.transform {
+            Some((transform, dimensions)) => transform.apply(self.rgba, dimensions),
+            None => self.rgba,
+        };
+    }
+}
+
+impl<C> PendingFrame<C>
+where
+    C: color::Color,
+    palette::rgb::Rgb:
+        palette::convert::FromColorUnclamped<<C as palette::WithAlpha<color::ScalarType>>::Color>,
+{
+    pub fn finish(self) -> Result<(Frame<C>, Option<color::quantize::QuantizeReport>), Error> {
+        let frame = match self {
+            Self::Indexed(frame) => return Ok((frame, None)),
+            Self::Rgba(frame) => frame,
+        };
+
+        let (delay, dispose, origin, dimensions) =
+            (frame.delay, frame.dispose, frame.origin, frame.dimensions);
+        let (quantizer, transparency) = (frame.quantizer.clone(), frame.transparency);
+        let (pal, indices, transparent_index, report) = quantize_rgba(
+            frame.transformed_rgba(),
+            (dimensions.0 as usize, dimensions.1 as usize),
+            &quantizer,
+            &transparency,
+        )?;
+
+        return Ok((
+            Frame {
+                delay,
+                dispose,
+                origin,
+                dimensions,
+                palette: pal,
+                pixels_indexed: indices.into(),
+                transparent_index,
+                interlaced: false,
+                needs_input: false,
+            },
+            Some(report),
+        ));
+    }
+}
+
+// Finishes a pending frame right away, for decoders handing out finished frames, keeping track of
+// how well quantizing went
+pub(crate) fn finish_pending<C>(
+    pending: Option<PendingFrame<C>>,
+    report: &mut Option<color::quantize::QuantizeReport>,
+) -> Result<Option<Frame<C>>, Error>
+where
+    C: color::Color,
+    palette::rgb::Rgb:
+        palette::convert::FromColorUnclamped<<C as palette::WithAlpha<color::ScalarType>>::Color>,
+{
+    let Some(pending) = pending else {
+        return Ok(None);
+    };
+
+    let (frame, frame_report) = pending.finish()?;
+    *report = color::quantize::QuantizeReport::worst(*report, frame_report);
+
+    return Ok(Some(frame));
+}
+
 // TODO: figure this out
 // impl<C, T> Frame<C>
 // where
@@ -124,19 +417,255 @@ where
 //     }
 // }
 
+// How the decoders of formats that aren't already indexed quantize their frames, and whether inputs
+// larger than a frame can be are scaled down rather than rejected, which the decoders check as soon
+// as they're given these
+#[derive(Clone)]
+pub struct DecoderOptions {
+    pub quantizer: color::quantize::Quantizer,
+    pub transparency: color::transparency::Transparency,
+    pub downscale: bool,
+}
+
+impl Default for DecoderOptions {
+    fn default() -> Self {
+        return DecoderOptions {
+            quantizer: color::quantize::Quantizer::new(
+                256,
+                color::quantize::QuantizerType::IMAGEQUANT,
+            ),
+            transparency: color::transparency::Transparency::default(),
+            downscale: false,
+        };
+    }
+}
+
+// Frames are addressed with u16 the same as in GIFs, so that's as large as an input can be
+pub const MAX_DIMENSION: u32 = u16::MAX as u32;
+
+// Fails for inputs too large for a frame, unless they're allowed to be scaled down to fit
+pub(crate) fn check_dimensions((width, height): (u32, u32), downscale: bool) -> Result<(), Error> {
+    if downscale || (width <= MAX_DIMENSION && height <= MAX_DIMENSION) {
+        return Ok(());
+    }
+
+    return Err(Error::Decode(DecodeError::InvalidData(
+        None,
+        format!(
+            "The input is {}x{}, but frames can be at most {} pixels wide or high, unless it's \
+             scaled down to fit",
+            width, height, MAX_DIMENSION
+        ),
+    )));
+}
+
+// The size of an input once it's scaled down to fit in a frame, which is the same size if it
+// already did
+pub(crate) fn fit_dimensions((width, height): (u32, u32)) -> (u16, u16) {
+    let scale = fit_scale((width, height));
+    let fit = |length: u32| {
+        return ((length as f64 * scale).round() as u32).clamp(1, MAX_DIMENSION) as u16;
+    };
+
+    return (fit(width), fit(height));
+}
+
+fn fit_scale((width, height): (u32, u32)) -> f64 {
+    return (MAX_DIMENSION as f64 / cmp::max(width, height).max(1) as f64).min(1.);
+}
+
+// Scales a frame's pixels and its rectangle on the canvas down along with the canvas, returning
+// the pixels, origin and dimensions
+pub(crate) fn fit_frame(
+    rgba: vec::Vec<u8>,
+    origin: (u32, u32),
+    dimensions: (u32, u32),
+    canvas: (u32, u32),
+) -> (vec::Vec<u8>, (u16, u16), (u16, u16)) {
+    let scale = fit_scale(canvas);
+    if scale >= 1. {
+        return (
+            rgba,
+            (origin.0 as u16, origin.1 as u16),
+            (dimensions.0 as u16, dimensions.1 as u16),
+        );
+    }
+
+    // the edges are rounded outwards, so frames next to each other don't leave gaps
+    let (fit_width, fit_height) = fit_dimensions(canvas);
+    let scale_span = |start: u32, length: u32, fit_length: u16| {
+        let fit_start = ((start as f64 * scale).floor() as u32).min(fit_length as u32 - 1);
+        let fit_end = (((start + length) as f64 * scale).ceil() as u32).min(fit_length as u32);
+        return (fit_start as u16, cmp::max(fit_end - fit_start, 1) as u16);
+    };
+    let (x, width) = scale_span(origin.0, dimensions.0, fit_width);
+    let (y, height) = scale_span(origin.1, dimensions.1, fit_height);
+
+    let image = ::image::RgbaImage::from_raw(dimensions.0, dimensions.1, rgba)
+        .expect("frames have 4 bytes for every pixel");
+    let resized = ::image::imageops::resize(
+        &image,
+        width as u32,
+        height as u32,
+        ::image::imageops::FilterType::Triangle,
+    );
+
+    return (resized.into_raw(), (x, y), (width, height));
+}
+
+// Quantizes RGBA pixels into a palette and the pixels indexing into it, for decoders of formats
+// that aren't already indexed
+pub(crate) fn quantize_rgba<C>(
+    mut rgba: vec::Vec<u8>,
+    dimensions: (usize, usize),
+    quantizer: &color::quantize::Quantizer,
+    transparency: &color::transparency::Transparency,
+) -> Result<
+    (
+        Palette<C>,
+        vec::Vec<u8>,
+        Option<u8>,
+        color::quantize::QuantizeReport,
+    ),
+    Error,
+>
+where
+    C: color::Color,
+    palette::rgb::Rgb:
+        palette::convert::FromColorUnclamped<<C as palette::WithAlpha<color::ScalarType>>::Color>,
+{
+    transparency.apply(&mut rgba);
+
+    let (pixels, indices, report) = quantizer.run_with_report(
+        rgba.chunks(4)
+            .map(|chunk| {
+                let [r, g, b, a]: [u8; 4] = chunk.try_into().unwrap();
+                return (r, g, b, a);
+            })
+            .collect(),
+        dimensions,
+    )?;
+
+    let transparent_index = pixels.iter().position(|&x| x.3 == 0).map(|i| i as u8);
+
+    let quantized_pixels = pixels
+        .into_iter()
+        .map(|pixel| {
+            return from_rgba8([pixel.0, pixel.1, pixel.2, pixel.3]);
+        })
+        .collect::<vec::Vec<_>>();
+
+    return Ok((
+        Palette::new(quantized_pixels),
+        indices,
+        transparent_index,
+        report,
+    ));
+}
+
+// Expands the indexed pixels of a frame into RGBA, for encoders of formats that aren't indexed
+pub(crate) fn expand_rgba<C>(frame: &Frame<C>) -> vec::Vec<u8>
+where
+    C: color::Color,
+    palette::rgb::Rgb<color::EncodingType, color::ScalarType>:
+        palette::convert::FromColorUnclamped<<C as palette::WithAlpha<color::ScalarType>>::Color>,
+{
+    let pal = frame
+        .palette
+        .colors
+        .iter()
+        .map(to_rgba8)
+        .collect::<vec::Vec<_>>();
+
+    return frame
+        .pixels_indexed
+        .iter()
+        .flat_map(|&index| {
+            if Some(index) == frame.transparent_index {
+                return [0, 0, 0, 0];
+            }
+
+            return pal.get(index as usize).copied().unwrap_or([0, 0, 0, 0]);
+        })
+        .collect();
+}
+
+// A still image written in one of the formats the image crate can write, for tests that need an
+// input to decode
+#[cfg(test)]
+pub(crate) fn encode_test_image(
+    image: ::image::RgbaImage,
+    format: ::image::ImageFormat,
+) -> vec::Vec<u8> {
+    let mut output = io::Cursor::new(vec::Vec::new());
+    image.write_to(&mut output, format).unwrap();
+    return output.into_inner();
+}
+
 pub trait Decodable
 where
     <Self as Decodable>::OutputColor: color::Color,
 {
     type OutputColor;
 
-    fn decode(&mut self) -> Result<Option<Frame<Self::OutputColor>>, Box<dyn error::Error>>;
+    fn decode(&mut self) -> Result<Option<Frame<Self::OutputColor>>, Error>;
 
-    fn decode_all(
-        &mut self,
-    ) -> Result<Option<vec::Vec<Frame<Self::OutputColor>>>, Box<dyn error::Error>>;
+    // like decode, but leaves quantizing to PendingFrame::finish so it can happen somewhere else
+    fn decode_pending(&mut self) -> Result<Option<PendingFrame<Self::OutputColor>>, Error> {
+        return Ok(self.decode()?.map(PendingFrame::Indexed));
+    }
+
+    fn decode_all(&mut self) -> Result<Option<vec::Vec<Frame<Self::OutputColor>>>, Error>;
 
     fn get_dimensions(&self) -> (u16, u16);
+
+    // for formats that can have comments anywhere, only the ones read so far are there
+    fn metadata(&self) -> Metadata {
+        return Metadata::default();
+    }
+
+    // how well the frames decoded so far survived quantizing, for formats that needed it
+    fn quantize_report(&self) -> Option<color::quantize::QuantizeReport> {
+        return None;
+    }
+
+    // makes decoding fail on palette colors that the color space can't hand back unchanged, which
+    // only means something for formats that come with their own palette rather than a quantized one
+    fn set_verify_palette(&mut self, _verify_palette: bool) {}
+}
+
+// Yields the frames of a decoder, stopping after the first error as the decoder can't pick up again
+// after it
+pub struct DecoderIter<D> {
+    decoder: D,
+    failed: bool,
+}
+
+impl<D> DecoderIter<D> {
+    pub fn new(decoder: D) -> Self {
+        return DecoderIter {
+            decoder,
+            failed: false,
+        };
+    }
+}
+
+impl<D> Iterator for DecoderIter<D>
+where
+    D: Decodable,
+    D::OutputColor: color::Color,
+{
+    type Item = Result<Frame<D::OutputColor>, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.failed {
+            return None;
+        }
+
+        let res = self.decoder.decode().transpose();
+        self.failed = matches!(res, Some(Err(_)));
+        return res;
+    }
 }
 
 // can't use FromIterator as a super trait, as it requires more than just an iterator to encode all
@@ -147,10 +676,103 @@ where
 {
     type InputColor;
 
-    fn encode(&self, frame: Frame<Self::InputColor>) -> Result<(), Box<dyn error::Error>>;
+    fn encode(&self, frame: Frame<Self::InputColor>) -> Result<(), Error>;
 
-    fn encode_all(
-        &self,
-        frames: vec::Vec<Frame<Self::InputColor>>,
-    ) -> Result<(), Box<dyn error::Error>>;
+    fn encode_all(&self, frames: vec::Vec<Frame<Self::InputColor>>) -> Result<(), Error>;
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::ValueEnum;
+
+    use super::{DecodeError, DecoderOptions, InputFormat, Palette};
+    use crate::{color, Error};
+
+    // every channel value on its own and as grays, plus a grid of mixed colors
+    fn test_colors() -> Vec<u8> {
+        let mut colors = Vec::new();
+        for v in 0..=255u8 {
+            colors.extend([v, 0, 0, 0, v, 0, 0, 0, v, v, v, v, v, 255 - v, v / 2]);
+        }
+        for r in (0..=255u8).step_by(17) {
+            for g in (0..=255u8).step_by(17) {
+                for b in (0..=255u8).step_by(17) {
+                    colors.extend([r, g, b]);
+                }
+            }
+        }
+
+        return colors;
+    }
+
+    fn round_trip<C>(colors: &[u8])
+    where
+        C: color::Color,
+        palette::rgb::Rgb<color::EncodingType, color::ScalarType>:
+            palette::convert::FromColorUnclamped<
+                <C as palette::WithAlpha<color::ScalarType>>::Color,
+            >,
+    {
+        for chunk in colors.chunks(256 * 3) {
+            let palette = Palette::<C>::from_gif_format_verified(chunk).unwrap();
+            assert_eq!(palette.into_gif_format(), chunk);
+        }
+    }
+
+    #[test]
+    fn test_palette_round_trip() {
+        let colors = test_colors();
+        for color_space in color::ColorSpace::value_variants() {
+            match color_space {
+                color::ColorSpace::HSL => {
+                    round_trip::<palette::Hsla<color::EncodingType, color::ScalarType>>(&colors)
+                }
+                color::ColorSpace::HSV => {
+                    round_trip::<palette::Hsva<color::EncodingType, color::ScalarType>>(&colors)
+                }
+                color::ColorSpace::LCH => {
+                    round_trip::<palette::Lcha<color::WhitePoint, color::ScalarType>>(&colors)
+                }
+                // mixing needs linear RGB
+                color::ColorSpace::RGB => {
+                    round_trip::<palette::LinSrgba<color::ScalarType>>(&colors)
+                }
+                color::ColorSpace::LAB => {
+                    round_trip::<palette::Laba<color::WhitePoint, color::ScalarType>>(&colors)
+                }
+                color::ColorSpace::OKLAB => {
+                    round_trip::<palette::Oklaba<color::ScalarType>>(&colors)
+                }
+                color::ColorSpace::OKLCH => {
+                    round_trip::<palette::Oklcha<color::ScalarType>>(&colors)
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_downscale() {
+        type TestColor = palette::Lcha<color::WhitePoint, color::ScalarType>;
+
+        let input = super::encode_test_image(
+            image::RgbaImage::from_fn(70000, 2, |x, _| image::Rgba([(x % 256) as u8, 0, 0, 255])),
+            image::ImageFormat::Png,
+        );
+        let format = InputFormat::sniff(&input[..]).unwrap();
+
+        let result = format.open::<TestColor>(&input[..], &DecoderOptions::default());
+        assert!(matches!(
+            result,
+            Err(Error::Decode(DecodeError::InvalidData(..)))
+        ));
+
+        let options = DecoderOptions {
+            downscale: true,
+            ..DecoderOptions::default()
+        };
+        let mut decoder = format.open::<TestColor>(&input[..], &options).unwrap();
+        assert_eq!(decoder.get_dimensions(), (65535, 2));
+        let frame = decoder.decode().unwrap().unwrap();
+        assert_eq!((frame.origin, frame.dimensions), ((0, 0), (65535, 2)));
+    }
 }
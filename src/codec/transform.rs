@@ -0,0 +1,420 @@
+/* Resizing, cropping and padding the frames
+ *
+ * Frames often only cover the part of the image that changed, and transforming those on their own
+ * leaves seams, so every frame is drawn onto the canvas first and the whole canvas is transformed.
+ * The result gets quantized again into a frame covering the new canvas, which is cleared before
+ * the next one is drawn. The steps always go in this order:
+ *   crop: cuts a rectangle out of the canvas
+ *   resize: scales to the exact size, or with letterboxing to the largest size that fits in it
+ *     with the same aspect ratio, centered between transparent bars
+ *   pad: centers the image on a transparent canvas of at least the given size
+ */
+
+use std::cmp;
+use std::vec;
+
+use ::gif as gif_lib;
+use ::image::imageops;
+use clap::{builder::PossibleValue, ValueEnum};
+
+use super::{composite, Decodable, Frame, PendingFrame, RgbaFrame};
+use crate::codec;
+use crate::color;
+use crate::commandline::{self, CommandlineError};
+use crate::Error;
+
+commandline::define_cli_enum!(ResizeFilter, {
+    Nearest: ("nearest", "Picks the closest pixel, which keeps pixel art crisp"),
+    Bilinear: ("bilinear", "Interpolates between the 4 closest pixels"),
+    Lanczos: ("lanczos", "Lanczos with a window of 3, the sharpest but slowest"),
+});
+
+impl ResizeFilter {
+    fn filter_type(&self) -> imageops::FilterType {
+        return match self {
+            Self::Nearest => imageops::FilterType::Nearest,
+            Self::Bilinear => imageops::FilterType::Triangle,
+            Self::Lanczos => imageops::FilterType::Lanczos3,
+        };
+    }
+}
+
+#[derive(Clone, Copy)]
+pub struct Transform {
+    // origin and dimensions of the part of the canvas that's kept
+    pub crop: Option<((u16, u16), (u16, u16))>,
+    pub resize: Option<(u16, u16)>,
+    pub filter: ResizeFilter,
+    // keeps the aspect ratio when resizing, rather than stretching to the size
+    pub letterbox: bool,
+    pub pad: Option<(u16, u16)>,
+}
+
+impl Default for Transform {
+    // leaves the frames alone
+    fn default() -> Self {
+        return Transform {
+            crop: None,
+            resize: None,
+            filter: ResizeFilter::Lanczos,
+            letterbox: false,
+            pad: None,
+        };
+    }
+}
+
+impl Transform {
+    pub fn is_identity(&self) -> bool {
+        return self.crop.is_none() && self.resize.is_none() && self.pad.is_none();
+    }
+
+    // The dimensions of a canvas once it's transformed, failing when the crop doesn't fit in it
+    pub fn dimensions(&self, (width, height): (u16, u16)) -> Result<(u16, u16), Error> {
+        let mut dimensions = (width, height);
+
+        if let Some(((x, y), (crop_width, crop_height))) = self.crop {
+            if crop_width == 0
+                || crop_height == 0
+                || x as u32 + crop_width as u32 > width as u32
+                || y as u32 + crop_height as u32 > height as u32
+            {
+                return Err(Error::Commandline(CommandlineError::IncompatibleValue(
+                    None,
+                    format!(
+                        "Cropping {}x{}+{}+{} doesn't fit in the {}x{} input",
+                        crop_width, crop_height, x, y, width, height
+                    ),
+                )));
+            }
+
+            dimensions = (crop_width, crop_height);
+        }
+
+        if let Some(resize) = self.resize {
+            dimensions = resize;
+        }
+
+        if let Some((pad_width, pad_height)) = self.pad {
+            dimensions = (
+                cmp::max(dimensions.0, pad_width),
+                cmp::max(dimensions.1, pad_height),
+            );
+        }
+
+        return Ok(dimensions);
+    }
+
+    // Transforms the RGBA pixels of a whole canvas, which has to have passed dimensions already
+    pub fn apply(&self, rgba: vec::Vec<u8>, (width, height): (u16, u16)) -> vec::Vec<u8> {
+        let mut image = ::image::RgbaImage::from_raw(width as u32, height as u32, rgba)
+            .expect("canvases have 4 bytes for every pixel");
+
+        if let Some(((x, y), (crop_width, crop_height))) = self.crop {
+            image = imageops::crop_imm(
+                &image,
+                x as u32,
+                y as u32,
+                crop_width as u32,
+                crop_height as u32,
+            )
+            .to_image();
+        }
+
+        if let Some((resize_width, resize_height)) = self.resize {
+            let (width, height) = image.dimensions();
+            let scaled = if self.letterbox {
+                let scale = f64::min(
+                    resize_width as f64 / width as f64,
+                    resize_height as f64 / height as f64,
+                );
+                let fit = |length: u32, max: u16| {
+                    return ((length as f64 * scale).round() as u32).clamp(1, max as u32);
+                };
+                (fit(width, resize_width), fit(height, resize_height))
+            } else {
+                (resize_width as u32, resize_height as u32)
+            };
+
+            image = resize(image, scaled, self.filter);
+            image = pad(image, (resize_width as u32, resize_height as u32));
+        }
+
+        if let Some((pad_width, pad_height)) = self.pad {
+            let (width, height) = image.dimensions();
+            image = pad(
+                image,
+                (
+                    cmp::max(width, pad_width as u32),
+                    cmp::max(height, pad_height as u32),
+                ),
+            );
+        }
+
+        return image.into_raw();
+    }
+}
+
+// Filtering straight alpha would pull the color of transparent pixels, which is black on a cleared
+// canvas, into the edges next to them, so the colors are weighted by their alpha while resizing
+fn resize(
+    mut image: ::image::RgbaImage,
+    (width, height): (u32, u32),
+    filter: ResizeFilter,
+) -> ::image::RgbaImage {
+    if image.dimensions() == (width, height) {
+        return image;
+    }
+
+    if let ResizeFilter::Nearest = filter {
+        return imageops::resize(&image, width, height, filter.filter_type());
+    }
+
+    for pixel in image.pixels_mut() {
+        let alpha = pixel[3] as u32;
+        for channel in &mut pixel.0[..3] {
+            *channel = ((*channel as u32 * alpha + 127) / 255) as u8;
+        }
+    }
+
+    let mut resized = imageops::resize(&image, width, height, filter.filter_type());
+
+    for pixel in resized.pixels_mut() {
+        let alpha = pixel[3] as u32;
+        for channel in &mut pixel.0[..3] {
+            *channel = match alpha {
+                0 => 0,
+                _ => cmp::min((*channel as u32 * 255 + (alpha / 2)) / alpha, 255) as u8,
+            };
+        }
+    }
+
+    return resized;
+}
+
+// centers the image on a transparent canvas of the given size, which is at least as large
+fn pad(image: ::image::RgbaImage, (width, height): (u32, u32)) -> ::image::RgbaImage {
+    if image.dimensions() == (width, height) {
+        return image;
+    }
+
+    let mut canvas = ::image::RgbaImage::new(width, height);
+    imageops::replace(
+        &mut canvas,
+        &image,
+        ((width - image.width()) / 2) as i64,
+        ((height - image.height()) / 2) as i64,
+    );
+
+    return canvas;
+}
+
+// Transforms the frames of another decoder, quantizing the transformed canvases the same way the
+// decoders of formats that aren't indexed do
+pub struct TransformDecoder<'a, C> {
+    decoder: Box<dyn Decodable<OutputColor = C> + 'a>,
+    transform: Transform,
+    dimensions: (u16, u16),
+    compositor: composite::Compositor,
+    options: codec::DecoderOptions,
+    report: Option<color::quantize::QuantizeReport>,
+}
+
+impl<'a, C> TransformDecoder<'a, C>
+where
+    C: color::Color,
+{
+    pub fn new(
+        decoder: Box<dyn Decodable<OutputColor = C> + 'a>,
+        transform: Transform,
+    ) -> Result<Self, Error> {
+        let input_dimensions = decoder.get_dimensions();
+
+        return Ok(TransformDecoder {
+            dimensions: transform.dimensions(input_dimensions)?,
+            decoder,
+            transform,
+            compositor: composite::Compositor::new(input_dimensions),
+            options: codec::DecoderOptions::default(),
+            report: None,
+        });
+    }
+
+    pub fn with_options(mut self, options: codec::DecoderOptions) -> Self {
+        self.options = options;
+        return self;
+    }
+}
+
+impl<C> Decodable for TransformDecoder<'_, C>
+where
+    C: color::Color,
+    palette::rgb::Rgb:
+        palette::convert::FromColorUnclamped<<C as palette::WithAlpha<color::ScalarType>>::Color>,
+{
+    type OutputColor = C;
+
+    fn decode(&mut self) -> Result<Option<Frame<Self::OutputColor>>, Error> {
+        let pending = self.decode_pending()?;
+        return codec::finish_pending(pending, &mut self.report);
+    }
+
+    // only the compositing has to happen in order, transforming and quantizing the canvas is left
+    // for finishing the frame
+    fn decode_pending(&mut self) -> Result<Option<PendingFrame<Self::OutputColor>>, Error> {
+        let (delay, rgba) = match self.decoder.decode_pending()? {
+            Some(PendingFrame::Indexed(frame)) => {
+                let canvas = self.compositor.draw(&frame).to_vec();
+                (frame.delay, canvas)
+            }
+            Some(PendingFrame::Rgba(frame)) => {
+                let (delay, origin, dispose) = (frame.delay, frame.origin, frame.dispose);
+                let dimensions = frame.dimensions;
+                let rgba = frame.transformed_rgba();
+                let canvas = self
+                    .compositor
+                    .draw_rgba(&rgba, origin, dimensions, dispose)
+                    .to_vec();
+                (delay, canvas)
+            }
+            None => return Ok(None),
+        };
+
+        // every frame is the whole canvas as it's shown, so nothing of it should stay around for
+        // the next one
+        return Ok(Some(PendingFrame::Rgba(RgbaFrame {
+            delay,
+            dispose: gif_lib::DisposalMethod::Background,
+            origin: (0, 0),
+            dimensions: self.dimensions,
+            rgba,
+            transform: Some((self.transform, self.compositor.get_dimensions())),
+            quantizer: self.options.quantizer.clone(),
+            transparency: self.options.transparency,
+        })));
+    }
+
+    fn decode_all(&mut self) -> Result<Option<vec::Vec<Frame<Self::OutputColor>>>, Error> {
+        let mut frames = vec::Vec::new();
+
+        while let Some(frame) = self.decode()? {
+            frames.push(frame);
+        }
+
+        if !frames.is_empty() {
+            return Ok(Some(frames));
+        }
+
+        return Ok(None);
+    }
+
+    fn get_dimensions(&self) -> (u16, u16) {
+        return self.dimensions;
+    }
+
+    fn metadata(&self) -> codec::Metadata {
+        return self.decoder.metadata();
+    }
+
+    fn quantize_report(&self) -> Option<color::quantize::QuantizeReport> {
+        return color::quantize::QuantizeReport::worst(self.decoder.quantize_report(), self.report);
+    }
+
+    fn set_verify_palette(&mut self, verify_palette: bool) {
+        self.decoder.set_verify_palette(verify_palette);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ResizeFilter, Transform, TransformDecoder};
+    use crate::codec::{gif, Decodable};
+    use crate::color;
+
+    #[test]
+    fn test_transform() {
+        // a 4x2 canvas where every pixel has its own red
+        let rgba = (0..8u8).flat_map(|i| [i, 0, 0, 255]).collect::<Vec<_>>();
+        let reds = |rgba: &[u8]| {
+            return rgba
+                .chunks(4)
+                .map(|pixel| if pixel[3] == 0 { None } else { Some(pixel[0]) })
+                .collect::<Vec<_>>();
+        };
+
+        let transform = Transform {
+            crop: Some(((1, 0), (2, 2))),
+            ..Transform::default()
+        };
+        assert_eq!(transform.dimensions((4, 2)).unwrap(), (2, 2));
+        assert_eq!(
+            reds(&transform.apply(rgba.clone(), (4, 2))),
+            [Some(1), Some(2), Some(5), Some(6)]
+        );
+        assert!(transform.dimensions((2, 2)).is_err());
+
+        let transform = Transform {
+            resize: Some((2, 1)),
+            filter: ResizeFilter::Nearest,
+            ..Transform::default()
+        };
+        assert_eq!(transform.dimensions((4, 2)).unwrap(), (2, 1));
+        assert_eq!(transform.apply(rgba.clone(), (4, 2)).len(), 2 * 4);
+
+        // letterboxed into a square, the 2:1 canvas gets a transparent row above and below
+        let transform = Transform {
+            resize: Some((4, 4)),
+            filter: ResizeFilter::Nearest,
+            letterbox: true,
+            ..Transform::default()
+        };
+        let letterboxed = reds(&transform.apply(rgba.clone(), (4, 2)));
+        assert_eq!(letterboxed[..4], [None; 4]);
+        assert_eq!(letterboxed[4..12], (0..8).map(Some).collect::<Vec<_>>()[..]);
+        assert_eq!(letterboxed[12..], [None; 4]);
+
+        // padding never shrinks the canvas
+        let transform = Transform {
+            pad: Some((6, 1)),
+            ..Transform::default()
+        };
+        assert_eq!(transform.dimensions((4, 2)).unwrap(), (6, 2));
+        let padded = reds(&transform.apply(rgba, (4, 2)));
+        assert_eq!(
+            padded[..6],
+            [None, Some(0), Some(1), Some(2), Some(3), None]
+        );
+    }
+
+    #[test]
+    fn test_transform_decoder() {
+        type TestColor = palette::Lcha<color::WhitePoint, color::ScalarType>;
+
+        let input = include_bytes!("../../images/fidget_spinner.gif");
+        let mut decoder = gif::GifDecoder::<_, TestColor>::new(&input[..]).unwrap();
+        let dimensions = decoder.get_dimensions();
+        let frame_count = decoder.decode_all().unwrap().unwrap().len();
+
+        // every frame covers the whole transformed canvas, so nothing is left over from the
+        // input's frame rectangles
+        let transform = Transform {
+            crop: Some(((0, 0), (dimensions.0 / 2, dimensions.1))),
+            resize: Some((32, 32)),
+            letterbox: true,
+            pad: Some((40, 32)),
+            ..Transform::default()
+        };
+        let mut decoder = TransformDecoder::new(
+            Box::new(gif::GifDecoder::<_, TestColor>::new(&input[..]).unwrap()),
+            transform,
+        )
+        .unwrap();
+        assert_eq!(decoder.get_dimensions(), (40, 32));
+
+        let frames = decoder.decode_all().unwrap().unwrap();
+        assert_eq!(frames.len(), frame_count);
+        assert!(frames
+            .iter()
+            .all(|frame| (frame.origin, frame.dimensions) == ((0, 0), (40, 32))));
+        assert!(decoder.quantize_report().is_some());
+    }
+}
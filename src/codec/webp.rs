@@ -0,0 +1,586 @@
+use std::cell::RefCell;
+use std::cmp;
+use std::io;
+use std::marker::PhantomData;
+use std::vec;
+
+use ::gif as gif_lib;
+
+use super::{
+    composite, Decodable, DecodeError, Encodable, EncodeError, Frame, PendingFrame, RgbaFrame,
+};
+use crate::codec;
+use crate::color;
+use crate::Error;
+
+// frames in a WebP can't be any bigger than this, even though the canvas could be
+const MAX_DIMENSION: u16 = 16384;
+
+// RIFF containers start with their size, so the format is only known from the bytes after it
+pub fn has_signature(data: &[u8]) -> bool {
+    return data.len() >= 12 && &data[0..4] == b"RIFF" && &data[8..12] == b"WEBP";
+}
+
+pub struct WebpDecoder<R: io::BufRead + io::Seek, C> {
+    phantom: PhantomData<C>,
+    decoder: image_webp::WebPDecoder<R>,
+    remaining_frames: usize,
+    options: codec::DecoderOptions,
+    report: Option<color::quantize::QuantizeReport>,
+}
+
+impl<R, C> WebpDecoder<R, C>
+where
+    R: io::BufRead + io::Seek,
+    C: color::Color,
+{
+    pub fn new(read: R) -> Result<Self, Error> {
+        let mut decoder = read_info(read)?;
+
+        let remaining_frames = if decoder.is_animated() {
+            // disposed frames are cleared to transparent, the same as GIF
+            if let Err(e) = decoder.set_background_color([0, 0, 0, 0]) {
+                return Err(Error::Decode(DecodeError::Init(
+                    Some(Box::new(e)),
+                    "Could not set background color".to_owned(),
+                )));
+            }
+
+            decoder.num_frames() as usize
+        } else {
+            // a still WebP is treated as a single frame animation
+            1
+        };
+
+        return Ok(WebpDecoder {
+            phantom: PhantomData,
+            decoder,
+            remaining_frames,
+            options: codec::DecoderOptions::default(),
+            report: None,
+        });
+    }
+
+    pub fn with_options(mut self, options: codec::DecoderOptions) -> Result<Self, Error> {
+        self.options = options;
+        self.check_dimensions()?;
+        return Ok(self);
+    }
+
+    pub fn check_dimensions(&self) -> Result<(), Error> {
+        return codec::check_dimensions(self.raw_dimensions(), self.options.downscale);
+    }
+
+    fn raw_dimensions(&self) -> (u32, u32) {
+        return self.decoder.dimensions();
+    }
+}
+
+fn read_info<R: io::BufRead + io::Seek>(read: R) -> Result<image_webp::WebPDecoder<R>, Error> {
+    return match image_webp::WebPDecoder::new(read) {
+        Ok(decoder) => Ok(decoder),
+        Err(e) => Err(Error::Decode(DecodeError::Read(
+            Some(Box::new(e)),
+            "Could not read image".to_owned(),
+        ))),
+    };
+}
+
+// Only reads the chunk headers, so it's cheap enough to do before streaming the frames
+pub fn count_frames<R: io::BufRead + io::Seek>(read: R) -> Result<usize, Error> {
+    let decoder = read_info(read)?;
+
+    if decoder.is_animated() {
+        return Ok(decoder.num_frames() as usize);
+    }
+
+    return Ok(1);
+}
+
+impl<R, C> Decodable for WebpDecoder<R, C>
+where
+    R: io::BufRead + io::Seek,
+    C: color::Color,
+    palette::rgb::Rgb:
+        palette::convert::FromColorUnclamped<<C as palette::WithAlpha<color::ScalarType>>::Color>,
+{
+    type OutputColor = C;
+
+    fn decode(&mut self) -> Result<Option<Frame<C>>, Error> {
+        let pending = self.decode_pending()?;
+        return codec::finish_pending(pending, &mut self.report);
+    }
+
+    fn decode_pending(&mut self) -> Result<Option<PendingFrame<C>>, Error> {
+        if self.remaining_frames == 0 {
+            return Ok(None);
+        }
+
+        self.check_dimensions()?;
+        let buffer_size = self.decoder.output_buffer_size().ok_or_else(|| {
+            return DecodeError::InvalidData(None, "Image is too large".to_owned());
+        })?;
+        let mut buf = vec![0; buffer_size];
+
+        // frames come out already drawn onto the full canvas, so every frame replaces the last
+        let res = if self.decoder.is_animated() {
+            self.decoder.read_frame(&mut buf)
+        } else {
+            self.decoder.read_image(&mut buf).map(|_| 0)
+        };
+        let delay_ms = match res {
+            Ok(delay_ms) => delay_ms,
+            Err(e) => {
+                return Err(Error::Decode(DecodeError::FrameRead(
+                    Some(Box::new(e)),
+                    "Could not read frame".to_owned(),
+                )));
+            }
+        };
+        self.remaining_frames -= 1;
+
+        let rgba = if self.decoder.has_alpha() {
+            buf
+        } else {
+            buf.chunks(3)
+                .flat_map(|chunk| [chunk[0], chunk[1], chunk[2], 255])
+                .collect()
+        };
+
+        let (rgba, _, dimensions) =
+            codec::fit_frame(rgba, (0, 0), self.raw_dimensions(), self.raw_dimensions());
+
+        return Ok(Some(PendingFrame::Rgba(RgbaFrame {
+            // WebP durations are in milliseconds rather than centiseconds
+            delay: ((delay_ms + 5) / 10).min(u16::MAX as u32) as u16,
+            dispose: gif_lib::DisposalMethod::Background,
+            origin: (0, 0),
+            dimensions,
+            rgba,
+            transform: None,
+            quantizer: self.options.quantizer.clone(),
+            transparency: self.options.transparency,
+        })));
+    }
+
+    fn decode_all(&mut self) -> Result<Option<vec::Vec<Frame<C>>>, Error> {
+        let mut frames = vec::Vec::new();
+
+        while let Some(frame) = self.decode()? {
+            frames.push(frame);
+        }
+
+        if !frames.is_empty() {
+            return Ok(Some(frames));
+        }
+
+        return Ok(None);
+    }
+
+    fn quantize_report(&self) -> Option<color::quantize::QuantizeReport> {
+        return self.report;
+    }
+
+    fn metadata(&self) -> codec::Metadata {
+        if !self.decoder.is_animated() {
+            return codec::Metadata::default();
+        }
+
+        return codec::Metadata {
+            repeat: match self.decoder.loop_count() {
+                image_webp::LoopCount::Forever => gif_lib::Repeat::Infinite,
+                image_webp::LoopCount::Times(count) => {
+                    codec::Metadata::repeat_from_play_count(count.get() as u32)
+                }
+            },
+            ..codec::Metadata::default()
+        };
+    }
+
+    fn get_dimensions(&self) -> (u16, u16) {
+        return codec::fit_dimensions(self.raw_dimensions());
+    }
+}
+
+impl<R, C> IntoIterator for WebpDecoder<R, C>
+where
+    R: io::BufRead + io::Seek,
+    C: color::Color,
+    palette::rgb::Rgb:
+        palette::convert::FromColorUnclamped<<C as palette::WithAlpha<color::ScalarType>>::Color>,
+{
+    type Item = Result<Frame<C>, Error>;
+    type IntoIter = codec::DecoderIter<Self>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        return codec::DecoderIter::new(self);
+    }
+}
+
+// Writes an animated lossless WebP. The RIFF header needs the size of the whole file, so the
+// frames are kept encoded in memory until finish writes everything out.
+pub struct WebpEncoder<W: io::Write, C> {
+    phantom: PhantomData<C>,
+    writer: W,
+    width: u16,
+    height: u16,
+    frames: RefCell<vec::Vec<u8>>,
+    compositor: RefCell<composite::Compositor>,
+    metadata: codec::Metadata,
+}
+
+impl<W, C> WebpEncoder<W, C>
+where
+    W: io::Write,
+    C: color::Color,
+{
+    pub fn new(w: W, (width, height): (u16, u16)) -> Result<Self, Error> {
+        if width == 0 || height == 0 || width > MAX_DIMENSION || height > MAX_DIMENSION {
+            return Err(Error::Encode(EncodeError::Init(
+                None,
+                format!(
+                    "WebP frames must be between 1x1 and {}x{}, but got {}x{}",
+                    MAX_DIMENSION, MAX_DIMENSION, width, height
+                ),
+            )));
+        }
+
+        return Ok(WebpEncoder {
+            phantom: PhantomData,
+            writer: w,
+            width,
+            height,
+            frames: RefCell::new(vec::Vec::new()),
+            compositor: RefCell::new(composite::Compositor::new((width, height))),
+            metadata: codec::Metadata::default(),
+        });
+    }
+
+    // only the repeat count can be written, WebP has no place for the rest
+    pub fn set_metadata(&mut self, metadata: codec::Metadata) {
+        self.metadata = metadata;
+    }
+
+    pub fn write(&self, frame: Frame<C>) -> Result<(), Error>
+    where
+        palette::rgb::Rgb<color::EncodingType, color::ScalarType>:
+            palette::convert::FromColorUnclamped<
+                <C as palette::WithAlpha<color::ScalarType>>::Color,
+            >,
+    {
+        // WebP can't restore the previous frame and only allows even offsets, so every frame is
+        // written as the whole canvas the way it's meant to be shown
+        let mut compositor = self.compositor.borrow_mut();
+        let rgba = compositor.draw(&frame);
+        let (width, height) = (self.width as usize, self.height as usize);
+
+        let mut encoded = vec::Vec::new();
+        if let Err(e) = image_webp::WebPEncoder::new(&mut encoded).encode(
+            rgba,
+            width as u32,
+            height as u32,
+            image_webp::ColorType::Rgba8,
+        ) {
+            return Err(Error::Encode(EncodeError::FrameWrite(
+                Some(Box::new(e)),
+                "Could not encode frame".to_owned(),
+            )));
+        }
+
+        // the encoder writes a whole still image, of which only the VP8L chunk is needed
+        let Some(bitstream) = find_chunk(&encoded[..], b"VP8L") else {
+            return Err(Error::Encode(EncodeError::FrameWrite(
+                None,
+                "Encoded frame had no VP8L chunk".to_owned(),
+            )));
+        };
+
+        let mut anmf = vec::Vec::with_capacity(16 + bitstream.len());
+        anmf.extend_from_slice(&to_u24(0));
+        anmf.extend_from_slice(&to_u24(0));
+        anmf.extend_from_slice(&to_u24(width as u32 - 1));
+        anmf.extend_from_slice(&to_u24(height as u32 - 1));
+        anmf.extend_from_slice(&to_u24(frame.delay as u32 * 10));
+        // the canvas already has the previous frames drawn in, so it replaces them without blending
+        anmf.push(0b0000_0010);
+        anmf.extend_from_slice(bitstream);
+
+        write_chunk(&mut self.frames.borrow_mut(), b"ANMF", &anmf[..]);
+
+        return Ok(());
+    }
+
+    pub fn finish(mut self) -> Result<(), Error> {
+        let mut vp8x = vec::Vec::with_capacity(10);
+        // animation and alpha flags
+        vp8x.extend_from_slice(&[0b0001_0010, 0, 0, 0]);
+        vp8x.extend_from_slice(&to_u24(self.width as u32 - 1));
+        vp8x.extend_from_slice(&to_u24(self.height as u32 - 1));
+
+        // transparent background and the loop count
+        let mut anim = vec![0u8, 0, 0, 0];
+        anim.extend_from_slice(
+            &(cmp::min(self.metadata.play_count(), u16::MAX as u32) as u16).to_le_bytes(),
+        );
+
+        let mut output = vec::Vec::new();
+        output.extend_from_slice(b"WEBP");
+        write_chunk(&mut output, b"VP8X", &vp8x[..]);
+        write_chunk(&mut output, b"ANIM", &anim[..]);
+        output.append(&mut self.frames.borrow_mut());
+
+        let res = self
+            .writer
+            .write_all(b"RIFF")
+            .and_then(|_| self.writer.write_all(&(output.len() as u32).to_le_bytes()))
+            .and_then(|_| self.writer.write_all(&output[..]));
+
+        if let Err(e) = res {
+            return Err(Error::Encode(EncodeError::Write(
+                Some(Box::new(e)),
+                "Could not finish writing".to_owned(),
+            )));
+        }
+
+        return Ok(());
+    }
+}
+
+fn to_u24(value: u32) -> [u8; 3] {
+    let [a, b, c, _] = value.to_le_bytes();
+    return [a, b, c];
+}
+
+// The whole chunk with its header and padding, out of the chunks that follow the RIFF header
+fn find_chunk<'a>(data: &'a [u8], name: &[u8; 4]) -> Option<&'a [u8]> {
+    let mut offset = 12;
+    while offset + 8 <= data.len() {
+        let size = u32::from_le_bytes(data[offset + 4..offset + 8].try_into().unwrap()) as usize;
+        if offset + 8 + size > data.len() {
+            return None;
+        }
+
+        let end = cmp::min(offset + 8 + size + (size % 2), data.len());
+        if &data[offset..offset + 4] == name {
+            return Some(&data[offset..end]);
+        }
+        offset = end;
+    }
+
+    return None;
+}
+
+fn write_chunk(output: &mut vec::Vec<u8>, name: &[u8; 4], data: &[u8]) {
+    output.extend_from_slice(name);
+    output.extend_from_slice(&(data.len() as u32).to_le_bytes());
+    output.extend_from_slice(data);
+
+    // chunks are padded to an even size
+    if data.len() % 2 == 1 {
+        output.push(0);
+    }
+}
+
+impl<W, C> Encodable for WebpEncoder<W, C>
+where
+    W: io::Write,
+    C: color::Color,
+    palette::rgb::Rgb<color::EncodingType, color::ScalarType>:
+        palette::convert::FromColorUnclamped<<C as palette::WithAlpha<color::ScalarType>>::Color>,
+{
+    type InputColor = C;
+
+    fn encode(&self, frame: Frame<C>) -> Result<(), Error> {
+        return self.write(frame);
+    }
+
+    fn encode_all(&self, frames: vec::Vec<Frame<C>>) -> Result<(), Error> {
+        for frame in frames {
+            self.write(frame)?;
+        }
+        return Ok(());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+
+    use ::gif as gif_lib;
+
+    use super::{
+        count_frames, find_chunk, has_signature, to_u24, write_chunk, WebpDecoder, WebpEncoder,
+    };
+    use crate::codec::{self, composite, gif, Decodable};
+    use crate::color;
+
+    type TestColor = palette::Lcha<color::WhitePoint, color::ScalarType>;
+
+    fn decode_rgba(data: &[u8]) -> Vec<Vec<u8>> {
+        return WebpDecoder::<_, TestColor>::new(io::Cursor::new(data))
+            .unwrap()
+            .decode_all()
+            .unwrap()
+            .unwrap()
+            .iter()
+            .map(|frame| codec::expand_rgba(frame).to_vec())
+            .collect();
+    }
+
+    // the colors go through quantizing, so they only have to be close, other than fully
+    // transparent pixels where only the alpha matters
+    fn assert_rgba_eq(actual: &[u8], expected: &[u8]) {
+        assert_eq!(actual.len(), expected.len());
+        for (i, (a, e)) in actual.chunks(4).zip(expected.chunks(4)).enumerate() {
+            assert_eq!(a[3], e[3], "alpha of pixel {}", i);
+            if e[3] != 0 {
+                for channel in 0..3 {
+                    assert!(
+                        a[channel].abs_diff(e[channel]) <= 2,
+                        "{:?} != {:?} at {}",
+                        a,
+                        e,
+                        i
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_round_trip_pixels() {
+        let frame = |origin: (u16, u16), dimensions: (u16, u16), pixels: Vec<u8>| {
+            return codec::Frame::<TestColor> {
+                delay: 10,
+                dispose: gif_lib::DisposalMethod::Keep,
+                origin,
+                dimensions,
+                palette: codec::Palette::from_gif_format(&[255, 0, 0, 0, 0, 255, 0, 0, 0]),
+                pixels_indexed: pixels.into(),
+                transparent_index: Some(2),
+                interlaced: false,
+                needs_input: false,
+            };
+        };
+        // a red canvas with a transparent corner, then a blue square away from the origin that
+        // has a transparent pixel of its own
+        let frames = vec![
+            frame((0, 0), (4, 4), [vec![2], vec![0; 15]].concat()),
+            frame((1, 2), (2, 2), vec![1, 2, 1, 1]),
+        ];
+
+        let mut compositor = composite::Compositor::new((4, 4));
+        let expected = frames
+            .iter()
+            .map(|frame| compositor.draw(frame).to_vec())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            expected[1][(4 * 2 + 2) * 4..(4 * 2 + 3) * 4],
+            [255, 0, 0, 255]
+        );
+
+        let mut output = Vec::new();
+        let encoder = WebpEncoder::new(&mut output, (4, 4)).unwrap();
+        for frame in frames {
+            encoder.write(frame).unwrap();
+        }
+        encoder.finish().unwrap();
+
+        let decoded = decode_rgba(&output[..]);
+        assert_eq!(decoded.len(), expected.len());
+        for (actual, expected) in decoded.iter().zip(expected.iter()) {
+            assert_rgba_eq(actual, expected);
+        }
+    }
+
+    #[test]
+    fn test_decode_foreign_animation() {
+        let vp8l = |rgba: &[u8], (width, height): (u32, u32)| {
+            let mut encoded = Vec::new();
+            image_webp::WebPEncoder::new(&mut encoded)
+                .encode(rgba, width, height, image_webp::ColorType::Rgba8)
+                .unwrap();
+            return find_chunk(&encoded[..], b"VP8L").unwrap().to_vec();
+        };
+
+        // unlike what the encoder writes, the second frame only covers part of the canvas and is
+        // blended over the first
+        let red = [255, 0, 0, 255].repeat(16);
+        let blue = [[0, 0, 255, 255].repeat(3), vec![0; 4]].concat();
+        let mut frames = Vec::new();
+        for (origin, dimensions, rgba, flags) in [
+            ((0, 0), (4, 4), &red, 0b0000_0010),
+            ((2, 2), (2, 2), &blue, 0b0000_0000),
+        ] {
+            let mut anmf = Vec::new();
+            anmf.extend_from_slice(&to_u24(origin.0 / 2));
+            anmf.extend_from_slice(&to_u24(origin.1 / 2));
+            anmf.extend_from_slice(&to_u24(dimensions.0 - 1));
+            anmf.extend_from_slice(&to_u24(dimensions.1 - 1));
+            anmf.extend_from_slice(&to_u24(100));
+            anmf.push(flags);
+            anmf.extend_from_slice(&vp8l(rgba, dimensions));
+            write_chunk(&mut frames, b"ANMF", &anmf[..]);
+        }
+
+        let mut vp8x = vec![0b0001_0010, 0, 0, 0];
+        vp8x.extend_from_slice(&to_u24(3));
+        vp8x.extend_from_slice(&to_u24(3));
+        let mut webp = b"WEBP".to_vec();
+        write_chunk(&mut webp, b"VP8X", &vp8x[..]);
+        write_chunk(&mut webp, b"ANIM", &[0, 0, 0, 0, 0, 0]);
+        webp.extend_from_slice(&frames[..]);
+        let data = [
+            b"RIFF".to_vec(),
+            (webp.len() as u32).to_le_bytes().to_vec(),
+            webp,
+        ]
+        .concat();
+
+        let decoded = decode_rgba(&data[..]);
+        assert_eq!(decoded.len(), 2);
+        assert_rgba_eq(&decoded[0], &red);
+
+        let mut expected = red.clone();
+        for (x, y) in [(2, 2), (3, 2), (2, 3)] {
+            expected[(y * 4 + x) * 4..(y * 4 + x + 1) * 4].copy_from_slice(&[0, 0, 255, 255]);
+        }
+        assert_rgba_eq(&decoded[1], &expected);
+    }
+
+    #[test]
+    fn test_encode_from_gif() {
+        let input = include_bytes!("../../images/fidget_spinner.gif");
+        let mut decoder =
+            gif::GifDecoder::<_, palette::Lcha<color::WhitePoint, color::ScalarType>>::new(
+                &input[..],
+            )
+            .unwrap();
+        let dimensions = decoder.get_dimensions();
+        let frames = decoder.decode_all().unwrap().unwrap();
+        let delays = frames.iter().map(|f| f.delay).collect::<Vec<_>>();
+
+        let mut output = Vec::new();
+        let encoder = WebpEncoder::new(&mut output, dimensions).unwrap();
+        for frame in frames {
+            encoder.write(frame).unwrap();
+        }
+        encoder.finish().unwrap();
+
+        assert!(has_signature(&output[..]));
+        assert_eq!(
+            count_frames(io::Cursor::new(&output[..])).unwrap(),
+            delays.len()
+        );
+
+        let mut decoder =
+            WebpDecoder::<_, palette::Lcha<color::WhitePoint, color::ScalarType>>::new(
+                io::Cursor::new(&output[..]),
+            )
+            .unwrap();
+        assert_eq!(decoder.get_dimensions(), dimensions);
+
+        let decoded = decoder.decode_all().unwrap().unwrap();
+        assert_eq!(decoded.iter().map(|f| f.delay).collect::<Vec<_>>(), delays);
+    }
+}
@@ -0,0 +1,248 @@
+/* Photoshop style blend modes
+ *
+ * All of the blending happens in sRGB regardless of the color space the colors are in, since
+ * that's how image editors define them. The formulas follow the W3C compositing spec:
+ * https://www.w3.org/TR/compositing-1/#blending
+ *
+ * The bottom color is the base (the pixel from the image) and the top color is the blend layer
+ * (the gradient color). The alpha of the bottom is always kept so transparency is preserved.
+ */
+
+use super::{Color, ColorType, ScalarType};
+use crate::commandline;
+use clap::{builder::PossibleValue, ValueEnum};
+
+commandline::define_cli_enum!(BlendMode, {
+    Multiply: ("multiply", "Multiplies the colors, always resulting in a darker color"),
+    Screen: ("screen", "Inverse of multiply, always resulting in a lighter color"),
+    Overlay: ("overlay", "Multiplies or screens depending on the bottom color, keeping its highlights and shadows"),
+    SoftLight: ("soft_light", "Darkens or lightens depending on the top color, like a diffused spotlight"),
+    HardLight: ("hard_light", "Multiplies or screens depending on the top color, like a harsh spotlight"),
+    ColorDodge: ("color_dodge", "Brightens the bottom color to reflect the top color"),
+    ColorBurn: ("color_burn", "Darkens the bottom color to reflect the top color"),
+    Hue: ("hue", "Takes the hue of the top color, keeping the saturation and luminosity of the bottom"),
+    Saturation: ("saturation", "Takes the saturation of the top color, keeping the hue and luminosity of the bottom"),
+    Color: ("color", "Takes the hue and saturation of the top color, keeping the luminosity of the bottom"),
+    Luminosity: ("luminosity", "Takes the luminosity of the top color, keeping the hue and saturation of the bottom"),
+});
+
+type Channels = [ScalarType; 3];
+
+pub fn blend<C>(bottom: &C, top: &C, mode: BlendMode) -> C
+where
+    C: Color,
+{
+    let bottom_rgba: ColorType = bottom.clone().into_color();
+    let top_rgba: ColorType = top.clone().into_color();
+
+    let b = [bottom_rgba.red, bottom_rgba.green, bottom_rgba.blue];
+    let s = [top_rgba.red, top_rgba.green, top_rgba.blue];
+
+    let [red, green, blue] = match mode {
+        BlendMode::Multiply => separable(b, s, multiply),
+        BlendMode::Screen => separable(b, s, screen),
+        BlendMode::Overlay => separable(b, s, overlay),
+        BlendMode::SoftLight => separable(b, s, soft_light),
+        BlendMode::HardLight => separable(b, s, hard_light),
+        BlendMode::ColorDodge => separable(b, s, color_dodge),
+        BlendMode::ColorBurn => separable(b, s, color_burn),
+        BlendMode::Hue => set_lum(set_sat(s, sat(b)), lum(b)),
+        BlendMode::Saturation => set_lum(set_sat(b, sat(s)), lum(b)),
+        BlendMode::Color => set_lum(s, lum(b)),
+        BlendMode::Luminosity => set_lum(b, lum(s)),
+    };
+
+    return C::from_color(ColorType::new(red, green, blue, bottom_rgba.alpha));
+}
+
+fn separable(b: Channels, s: Channels, f: fn(ScalarType, ScalarType) -> ScalarType) -> Channels {
+    return [f(b[0], s[0]), f(b[1], s[1]), f(b[2], s[2])];
+}
+
+fn multiply(b: ScalarType, s: ScalarType) -> ScalarType {
+    return b * s;
+}
+
+fn screen(b: ScalarType, s: ScalarType) -> ScalarType {
+    return b + s - (b * s);
+}
+
+fn overlay(b: ScalarType, s: ScalarType) -> ScalarType {
+    // overlay is hard light with the layers swapped
+    return hard_light(s, b);
+}
+
+fn hard_light(b: ScalarType, s: ScalarType) -> ScalarType {
+    if s <= 0.5 {
+        return multiply(b, 2. * s);
+    }
+
+    return screen(b, (2. * s) - 1.);
+}
+
+fn soft_light(b: ScalarType, s: ScalarType) -> ScalarType {
+    if s <= 0.5 {
+        return b - ((1. - (2. * s)) * b * (1. - b));
+    }
+
+    let d = if b <= 0.25 {
+        ((16. * b - 12.) * b + 4.) * b
+    } else {
+        b.sqrt()
+    };
+
+    return b + ((2. * s) - 1.) * (d - b);
+}
+
+fn color_dodge(b: ScalarType, s: ScalarType) -> ScalarType {
+    if b <= 0. {
+        return 0.;
+    }
+
+    if s >= 1. {
+        return 1.;
+    }
+
+    return ScalarType::min(1., b / (1. - s));
+}
+
+fn color_burn(b: ScalarType, s: ScalarType) -> ScalarType {
+    if b >= 1. {
+        return 1.;
+    }
+
+    if s <= 0. {
+        return 0.;
+    }
+
+    return 1. - ScalarType::min(1., (1. - b) / s);
+}
+
+fn lum(c: Channels) -> ScalarType {
+    return (0.3 * c[0]) + (0.59 * c[1]) + (0.11 * c[2]);
+}
+
+fn clip_color(c: Channels) -> Channels {
+    let l = lum(c);
+    let n = c[0].min(c[1]).min(c[2]);
+    let x = c[0].max(c[1]).max(c[2]);
+
+    return c.map(|channel| {
+        let mut clipped = channel;
+        if n < 0. {
+            clipped = l + (((clipped - l) * l) / (l - n));
+        }
+
+        if x > 1. {
+            clipped = l + (((clipped - l) * (1. - l)) / (x - l));
+        }
+
+        return clipped;
+    });
+}
+
+fn set_lum(c: Channels, l: ScalarType) -> Channels {
+    let d = l - lum(c);
+    return clip_color(c.map(|channel| channel + d));
+}
+
+fn sat(c: Channels) -> ScalarType {
+    return c[0].max(c[1]).max(c[2]) - c[0].min(c[1]).min(c[2]);
+}
+
+fn set_sat(c: Channels, s: ScalarType) -> Channels {
+    let max = c[0].max(c[1]).max(c[2]);
+    let min = c[0].min(c[1]).min(c[2]);
+
+    if max <= min {
+        return [0., 0., 0.];
+    }
+
+    return c.map(|channel| ((channel - min) * s) / (max - min));
+}
+
+#[cfg(test)]
+mod tests {
+    use palette::{FromColor, Hsla};
+
+    use super::{blend, BlendMode};
+    use crate::color;
+
+    type TestColor = Hsla<color::EncodingType, color::ScalarType>;
+
+    fn rgba(
+        r: color::ScalarType,
+        g: color::ScalarType,
+        b: color::ScalarType,
+        a: color::ScalarType,
+    ) -> TestColor {
+        return TestColor::from_color(color::ColorType::new(r, g, b, a));
+    }
+
+    fn assert_close(actual: TestColor, expected: TestColor) {
+        let actual = color::ColorType::from_color(actual);
+        let expected = color::ColorType::from_color(expected);
+        let pairs = [
+            (actual.red, expected.red),
+            (actual.green, expected.green),
+            (actual.blue, expected.blue),
+            (actual.alpha, expected.alpha),
+        ];
+        for (a, e) in pairs {
+            assert!(
+                (a - e).abs() < 1e-3,
+                "Expected {:?} but got {:?}",
+                expected,
+                actual
+            );
+        }
+    }
+
+    #[test]
+    fn test_blend_identities() {
+        let bottom = rgba(0.2, 0.4, 0.6, 1.);
+        let white = rgba(1., 1., 1., 1.);
+        let black = rgba(0., 0., 0., 1.);
+
+        assert_close(blend(&bottom, &white, BlendMode::Multiply), bottom);
+        assert_close(blend(&bottom, &black, BlendMode::Screen), bottom);
+        assert_close(blend(&bottom, &black, BlendMode::ColorDodge), bottom);
+        assert_close(blend(&bottom, &white, BlendMode::ColorBurn), bottom);
+    }
+
+    #[test]
+    fn test_blend_separable() {
+        let bottom = rgba(0.25, 0.5, 0.75, 1.);
+        let top = rgba(0.5, 0.5, 0.5, 1.);
+
+        assert_close(
+            blend(&bottom, &top, BlendMode::Multiply),
+            rgba(0.125, 0.25, 0.375, 1.),
+        );
+        assert_close(
+            blend(&bottom, &top, BlendMode::Screen),
+            rgba(0.625, 0.75, 0.875, 1.),
+        );
+        assert_close(blend(&bottom, &top, BlendMode::Overlay), bottom);
+        assert_close(blend(&bottom, &top, BlendMode::HardLight), bottom);
+        assert_close(blend(&bottom, &top, BlendMode::SoftLight), bottom);
+    }
+
+    #[test]
+    fn test_blend_non_separable() {
+        let gray = rgba(0.5, 0.5, 0.5, 0.5);
+        let red = rgba(1., 0., 0., 1.);
+
+        // gray has no saturation, so taking only its hue or saturation keeps it gray
+        assert_close(blend(&gray, &red, BlendMode::Hue), gray);
+        assert_close(blend(&gray, &red, BlendMode::Saturation), gray);
+
+        let colored = color::ColorType::from_color(blend(&gray, &red, BlendMode::Color));
+        assert!(colored.red > colored.green);
+        assert!((colored.green - colored.blue).abs() < 1e-3);
+        assert!((colored.alpha - 0.5).abs() < 1e-3);
+
+        let lightened = blend(&red, &gray, BlendMode::Luminosity);
+        assert!((lightened.hue.to_positive_degrees() - red.hue.to_positive_degrees()).abs() < 1.);
+    }
+}
@@ -4,7 +4,7 @@ use clap::{builder::PossibleValue, ValueEnum};
 use palette::gradient;
 use palette::Mix;
 
-use super::{Color, ScalarType};
+use super::{Color, ColorType, ScalarType};
 use crate::commandline;
 
 commandline::define_cli_enum!(GradientGeneratorType, {
@@ -12,6 +12,43 @@ commandline::define_cli_enum!(GradientGeneratorType, {
     Continuous: ("continuous", "where palette generates it, taking into account all colors"),
 });
 
+// What the gradient is generated from, independent of the color space it's generated in
+#[derive(Clone)]
+pub struct GradientOptions {
+    pub colors: vec::Vec<ColorType>,
+    pub generator: GradientGeneratorType,
+}
+
+impl GradientOptions {
+    pub fn new(colors: vec::Vec<ColorType>, generator: GradientGeneratorType) -> Self {
+        return GradientOptions { colors, generator };
+    }
+
+    pub fn generate<C>(&self, frame_count: usize) -> vec::Vec<C>
+    where
+        C: Mix<Scalar = ScalarType> + Color,
+        palette::rgb::Rgb:
+            palette::convert::FromColorUnclamped<<C as palette::WithAlpha<ScalarType>>::Color>,
+    {
+        let gradient_desc =
+            GradientDescriptor::new(self.colors.iter().map(|c| C::from_color(*c)).collect());
+        return gradient_desc.generate(frame_count, self.generator);
+    }
+}
+
+impl Default for GradientOptions {
+    fn default() -> Self {
+        return GradientOptions::new(
+            vec![
+                ColorType::new(1., 0., 0., 1.),
+                ColorType::new(0., 1., 0., 1.),
+                ColorType::new(0., 0., 1., 1.),
+            ],
+            GradientGeneratorType::Discrete,
+        );
+    }
+}
+
 struct GradientKeyFrame<'a, C>
 where
     C: Mix<Scalar = ScalarType> + Sized,
@@ -60,7 +97,7 @@ where
     }
 
     fn generate_discrete(&self, frame_count: usize) -> vec::Vec<C> {
-        let mut gen = vec::Vec::<C>::new();
+        let mut generated = vec::Vec::<C>::new();
 
         for i in 0..frame_count {
             let global_position = (i as ScalarType) / (frame_count as ScalarType);
@@ -72,10 +109,10 @@ where
             let src = key_frame_src.color;
             let dest = key_frame_dest.color;
 
-            gen.push(src.mix(&dest, local_position));
+            generated.push(src.mix(dest, local_position));
         }
 
-        return gen;
+        return generated;
     }
 
     fn position_search<'a>(
@@ -114,7 +151,7 @@ where
 
 #[cfg(test)]
 mod tests {
-    use palette::{FromColor, Lcha};
+    use palette::{FromColor, Lcha, Oklaba, Oklcha};
 
     use crate::color;
 
@@ -130,8 +167,8 @@ mod tests {
         assert_eq!(colors.len(), 12);
 
         assert_eq!(colors[0].chroma, 0.0);
-        assert_eq!(colors[4].chroma, 0.0);
-        assert_eq!(colors[8].chroma, 0.0);
+        assert!(colors[4].chroma.abs() < 1e-4);
+        assert!(colors[8].chroma.abs() < 1e-4);
     }
 
     #[test]
@@ -146,7 +183,37 @@ mod tests {
         assert_eq!(colors.len(), 12);
 
         assert_eq!(colors[0].chroma, 0.0);
-        assert_eq!(colors[4].chroma, 0.0);
-        assert_eq!(colors[8].chroma, 0.0);
+        assert!(colors[4].chroma.abs() < 1e-4);
+        assert!(colors[8].chroma.abs() < 1e-4);
+    }
+
+    #[test]
+    fn test_generate_oklch() {
+        let grad_desc = color::gradient::GradientDescriptor::new(vec![
+            Oklcha::from_color(color::ColorType::new(1., 0., 0., 1.)),
+            Oklcha::from_color(color::ColorType::new(0., 0., 1., 1.)),
+        ]);
+        let colors = grad_desc.generate(8, color::gradient::GradientGeneratorType::Discrete);
+        assert_eq!(colors.len(), 8);
+
+        // custom mixing keeps the lightness of the base color in both Oklch and Oklab, and takes
+        // the hue and chroma of the gradient
+        let base = color::ColorType::new(0.2, 0.4, 0.6, 1.);
+        for gradient_color in colors {
+            let mixed = color::blend_colors(&Oklcha::from_color(base), &gradient_color, true);
+            assert!((mixed.l - Oklcha::from_color(base).l).abs() < 1e-6);
+            assert_eq!(mixed.hue, gradient_color.hue);
+            assert_eq!(mixed.chroma, gradient_color.chroma);
+
+            let mixed = color::blend_colors(
+                &Oklaba::from_color(base),
+                &Oklaba::from_color(gradient_color),
+                true,
+            );
+            let mixed = Oklcha::from_color(mixed);
+            assert!((mixed.l - Oklcha::from_color(base).l).abs() < 1e-6);
+            assert!((mixed.hue - gradient_color.hue).to_degrees().abs() < 1e-2);
+            assert!((mixed.chroma - gradient_color.chroma).abs() < 1e-5);
+        }
     }
 }
@@ -1,10 +1,12 @@
 use clap::{builder::PossibleValue, ValueEnum};
-use palette::{Clamp, FromColor, Hsla, Hsva, LabHue, Lcha, RgbHue};
+use palette::{Clamp, FromColor, Hsla, Hsva, LabHue, Lcha, OklabHue, Oklaba, Oklcha, RgbHue};
 
 use crate::commandline;
 
+pub mod blend;
 pub mod gradient;
 pub mod quantize;
+pub mod transparency;
 
 pub type ScalarType = f32;
 // TODO put behind a feature
@@ -30,6 +32,8 @@ pub trait Color:
     + palette::Mix<Scalar = ScalarType>
     + Clone
     + Sized
+    + Send
+    + Sync
 {
 }
 
@@ -42,6 +46,8 @@ impl<T> Color for T where
         + palette::Mix<Scalar = ScalarType>
         + Clone
         + Sized
+        + Send
+        + Sync
 {
 }
 
@@ -50,9 +56,39 @@ commandline::define_cli_enum!(MixingMode, {
     Custom: ("custom", "Mixes the color by taking the hue component of the other color, keeping the base luma and chroma"),
     Lab: ("lab", "Mixes the color by taking the color components of the other color, keeping the base lightness"),
     Linear: ("linear", "Uses palettee for linear mixing"),
+    BlendMultiply: ("blend_multiply", "Uses blending: multiply"),
+    BlendScreen: ("blend_screen", "Uses blending: screen"),
     BlendOverlay: ("blend_overlay", "Uses blending: overlay"),
+    BlendSoftLight: ("blend_soft_light", "Uses blending: soft light"),
+    BlendHardLight: ("blend_hard_light", "Uses blending: hard light"),
+    BlendColorDodge: ("blend_color_dodge", "Uses blending: color dodge"),
+    BlendColorBurn: ("blend_color_burn", "Uses blending: color burn"),
+    BlendHue: ("blend_hue", "Uses blending: hue"),
+    BlendSaturation: ("blend_saturation", "Uses blending: saturation"),
+    BlendColor: ("blend_color", "Uses blending: color"),
+    BlendLuminosity: ("blend_luminosity", "Uses blending: luminosity"),
 });
 
+impl MixingMode {
+    // the blend mode backing this mixing mode, if it's one of the blend_* ones
+    pub fn blend_mode(&self) -> Option<blend::BlendMode> {
+        return match self {
+            Self::BlendMultiply => Some(blend::BlendMode::Multiply),
+            Self::BlendScreen => Some(blend::BlendMode::Screen),
+            Self::BlendOverlay => Some(blend::BlendMode::Overlay),
+            Self::BlendSoftLight => Some(blend::BlendMode::SoftLight),
+            Self::BlendHardLight => Some(blend::BlendMode::HardLight),
+            Self::BlendColorDodge => Some(blend::BlendMode::ColorDodge),
+            Self::BlendColorBurn => Some(blend::BlendMode::ColorBurn),
+            Self::BlendHue => Some(blend::BlendMode::Hue),
+            Self::BlendSaturation => Some(blend::BlendMode::Saturation),
+            Self::BlendColor => Some(blend::BlendMode::Color),
+            Self::BlendLuminosity => Some(blend::BlendMode::Luminosity),
+            _ => None,
+        };
+    }
+}
+
 commandline::define_cli_enum!(ColorSpace, {
     HSL: (
         "hsl",
@@ -73,6 +109,14 @@ commandline::define_cli_enum!(ColorSpace, {
     LAB: (
         "lab",
         "The CIE L*a*b* (CIELAB) color space"
+    ),
+    OKLAB: (
+        "oklab",
+        "Oklab is built like L*a*b*, but predicts lightness, chroma and hue more evenly, so there's no shift towards purple when blues get lighter or darker."
+    ),
+    OKLCH: (
+        "oklch",
+        "The cylindrical version of Oklab, the way L*C*h° is for L*a*b*."
     )
 });
 
@@ -108,6 +152,38 @@ impl Componentize<LabHue<ScalarType>, ScalarType, ScalarType, ScalarType>
     }
 }
 
+impl Componentize<OklabHue<ScalarType>, ScalarType, ScalarType, ScalarType> for Oklcha<ScalarType> {
+    fn get_components(&self) -> (OklabHue<ScalarType>, ScalarType, ScalarType, ScalarType) {
+        let (l, c, h, a) = self.into_components();
+        return (h, c, l, a);
+    }
+
+    fn from_components(
+        h: OklabHue<ScalarType>,
+        c: ScalarType,
+        l: ScalarType,
+        a: ScalarType,
+    ) -> Self {
+        return Oklcha::from_components((l, c, h, a)).clamp();
+    }
+}
+
+// Oklab has no hue of its own, so it goes through Oklch, the same as it's converted
+impl Componentize<OklabHue<ScalarType>, ScalarType, ScalarType, ScalarType> for Oklaba<ScalarType> {
+    fn get_components(&self) -> (OklabHue<ScalarType>, ScalarType, ScalarType, ScalarType) {
+        return Oklcha::from_color(*self).get_components();
+    }
+
+    fn from_components(
+        h: OklabHue<ScalarType>,
+        c: ScalarType,
+        l: ScalarType,
+        a: ScalarType,
+    ) -> Self {
+        return Oklaba::from_color(Oklcha::from_components((l, c, h, a)).clamp());
+    }
+}
+
 impl Componentize<RgbHue<ScalarType>, ScalarType, ScalarType, ScalarType>
     for Hsla<EncodingType, ScalarType>
 {
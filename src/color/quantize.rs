@@ -1,25 +1,85 @@
+use std::cmp;
 use std::collections::hash_map;
-use std::error;
 use std::vec;
 
+use clap::{builder::PossibleValue, ValueEnum};
 use imagequant;
 
+use crate::commandline;
+use crate::Error;
+
+pub mod dither;
+
 crate::error_utils::define_error!(
     QuantizeError, {
         InvalidType: "The given quantizer is not a valid one",
+        TooManyColors: "The image has more colors than the quantizer allows",
+        InvalidSetting: "The given setting is out of range",
+        Failed: "The quantizer failed",
     }
 );
 
-#[derive(Debug)]
-pub enum QuantizerType {
-    IDENTITY,
-    IMAGEQUANT,
-    SCALAR,
+commandline::define_cli_enum!(QuantizerType, {
+    IDENTITY: ("identity", "Keeps every color as is, only works for images that already have few enough colors"),
+    IMAGEQUANT: ("imagequant", "Uses libimagequant, which picks the palette by perceptual quality and dithers"),
+    SCALAR: ("scalar", "Splits each RGB channel into evenly spaced buckets, fast but crude"),
+    POPULOSITY: ("populosity", "Keeps the most common colors and maps the rest to the closest one"),
+    MEDIANCUT: ("mediancut", "Repeatedly splits the colors in half along their widest channel"),
+    OCTREE: ("octree", "Builds a tree of the color bits and merges the least used branches"),
+    KMEANS: ("kmeans", "Refines the median cut palette by moving each color to the center of its cluster"),
+});
+
+type Rgb = (u8, u8, u8);
+
+// unique colors along with how many pixels have them
+type Histogram = vec::Vec<(Rgb, usize)>;
+
+// picks up to the given number of colors from the histogram, returning them along with the palette
+// index for every histogram entry
+type PaletteAlgorithm = fn(&Histogram, usize) -> (vec::Vec<Rgb>, vec::Vec<usize>);
+
+// How close the quantized image came to the original, so callers can retry with other settings
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct QuantizeReport {
+    // libimagequant's 0-100 estimate, only there when it did the quantizing
+    pub quality: Option<u8>,
+    // mean squared error of the opaque pixels, averaged over the RGB channels on a 0-255 scale
+    pub error: f64,
 }
 
+impl QuantizeReport {
+    // the worse of the two, for summing up a whole animation
+    pub fn worst(a: Option<Self>, b: Option<Self>) -> Option<Self> {
+        return match (a, b) {
+            (Some(a), Some(b)) => Some(QuantizeReport {
+                quality: match (a.quality, b.quality) {
+                    (Some(qa), Some(qb)) => Some(cmp::min(qa, qb)),
+                    (qa, qb) => qa.or(qb),
+                },
+                error: a.error.max(b.error),
+            }),
+            (a, b) => a.or(b),
+        };
+    }
+}
+
+#[derive(Clone)]
 pub struct Quantizer {
     pub max_color_count: usize,
     pub quantizer_type: QuantizerType,
+    pub dither_type: dither::DitherType,
+    // 0 turns dithering off and 1 is full strength
+    pub dithering_level: f32,
+
+    // the 0-100 quality range, where quantizing fails if the minimum can't be reached
+    // only used by IMAGEQUANT
+    pub min_quality: u8,
+    pub max_quality: u8,
+    // 1 is the slowest and best, 10 the fastest, only used by IMAGEQUANT
+    pub speed: i32,
+
+    // how many of the lowest bits of the palette colors are dropped, which compresses better
+    pub posterization: u8,
 }
 
 impl Quantizer {
@@ -27,6 +87,12 @@ impl Quantizer {
         return Quantizer {
             max_color_count,
             quantizer_type,
+            dither_type: dither::DitherType::FloydSteinberg,
+            dithering_level: 1.0,
+            min_quality: 0,
+            max_quality: 100,
+            speed: 5,
+            posterization: 0,
         };
     }
 
@@ -34,16 +100,163 @@ impl Quantizer {
         &self,
         img: vec::Vec<(u8, u8, u8, u8)>,
         dimensions: (usize, usize),
-    ) -> Result<(vec::Vec<(u8, u8, u8, u8)>, vec::Vec<u8>), Box<dyn error::Error>> {
+    ) -> Result<(vec::Vec<(u8, u8, u8, u8)>, vec::Vec<u8>), Error> {
+        return self
+            .run_with_report(img, dimensions)
+            .map(|(palette, indices, _)| (palette, indices));
+    }
+
+    pub fn run_with_report(
+        &self,
+        img: vec::Vec<(u8, u8, u8, u8)>,
+        dimensions: (usize, usize),
+    ) -> Result<(vec::Vec<(u8, u8, u8, u8)>, vec::Vec<u8>, QuantizeReport), Error> {
+        self.validate()?;
+
+        let (palette, indices, quality) = self.quantize(&img, dimensions)?;
+        let error = mean_squared_error(&img[..], &palette[..], &indices[..]);
+
+        return Ok((palette, indices, QuantizeReport { quality, error }));
+    }
+
+    fn validate(&self) -> Result<(), Error> {
+        let invalid = |message: String| -> Result<(), Error> {
+            return Err(Error::Quantize(QuantizeError::InvalidSetting(
+                None, message,
+            )));
+        };
+
+        if !(2..=256).contains(&self.max_color_count) {
+            return invalid(format!(
+                "The max color count must be between 2 and 256, but got {}",
+                self.max_color_count
+            ));
+        }
+
+        if self.min_quality > self.max_quality || self.max_quality > 100 {
+            return invalid(format!(
+                "Invalid quality range {}-{}",
+                self.min_quality, self.max_quality
+            ));
+        }
+
+        if !(1..=10).contains(&self.speed) {
+            return invalid(format!(
+                "The speed must be between 1 and 10, but got {}",
+                self.speed
+            ));
+        }
+
+        if self.posterization > 4 {
+            return invalid(format!(
+                "At most 4 bits can be posterized, but got {}",
+                self.posterization
+            ));
+        }
+
+        if !(0.0..=1.0).contains(&self.dithering_level) {
+            return invalid(format!(
+                "The dithering level must be between 0 and 1, but got {}",
+                self.dithering_level
+            ));
+        }
+
+        return Ok(());
+    }
+
+    fn quantize(
+        &self,
+        img: &[(u8, u8, u8, u8)],
+        dimensions: (usize, usize),
+    ) -> Result<(vec::Vec<(u8, u8, u8, u8)>, vec::Vec<u8>, Option<u8>), Error> {
+        let native = |algorithm: PaletteAlgorithm| {
+            return self
+                .run_native(img, dimensions, algorithm)
+                .map(|(palette, indices)| (palette, indices, None));
+        };
+
         return match self.quantizer_type {
-            QuantizerType::IDENTITY => quantize_identity(img, dimensions),
-            QuantizerType::IMAGEQUANT => quantize_image_quant(img, dimensions),
-            _ => Err(Box::new(QuantizeError::InvalidType(
-                None,
-                format!("Invalid quantizer {:?}", self.quantizer_type),
-            ))),
+            QuantizerType::IDENTITY => {
+                let (palette, indices) = quantize_identity(img.to_vec(), dimensions)?;
+                if palette.len() > self.max_color_count {
+                    return Err(Error::Quantize(QuantizeError::TooManyColors(
+                        None,
+                        format!(
+                            "Found {} colors but only {} are allowed",
+                            palette.len(),
+                            self.max_color_count
+                        ),
+                    )));
+                }
+
+                Ok((
+                    palette,
+                    indices.into_iter().map(|i| i as u8).collect(),
+                    None,
+                ))
+            }
+            // libimagequant's own dithering is Floyd-Steinberg, otherwise only its palette is used
+            QuantizerType::IMAGEQUANT => match self.dither_type {
+                dither::DitherType::FloydSteinberg => {
+                    quantize_image_quant(img.to_vec(), dimensions, self, self.dithering_level)
+                }
+                dither::DitherType::None => {
+                    quantize_image_quant(img.to_vec(), dimensions, self, 0.0)
+                }
+                _ => {
+                    let (palette, _, quality) =
+                        quantize_image_quant(img.to_vec(), dimensions, self, 0.0)?;
+                    let (palette, indices) = self.dither(img, dimensions, palette, None)?;
+                    Ok((palette, indices, quality))
+                }
+            },
+            QuantizerType::SCALAR => native(palette_scalar),
+            QuantizerType::POPULOSITY => native(palette_populosity),
+            QuantizerType::MEDIANCUT => native(palette_median_cut),
+            QuantizerType::OCTREE => native(palette_octree),
+            QuantizerType::KMEANS => native(palette_kmeans),
         };
     }
+
+    fn run_native(
+        &self,
+        img: &[(u8, u8, u8, u8)],
+        dimensions: (usize, usize),
+        algorithm: PaletteAlgorithm,
+    ) -> Result<(vec::Vec<(u8, u8, u8, u8)>, vec::Vec<u8>), Error> {
+        let (mut palette, indices) = quantize_with(img, self.max_color_count, algorithm)?;
+        for color in palette.iter_mut() {
+            color.0 = posterize(color.0, self.posterization);
+            color.1 = posterize(color.1, self.posterization);
+            color.2 = posterize(color.2, self.posterization);
+        }
+
+        return self.dither(img, dimensions, palette, Some(indices));
+    }
+
+    // without dithering, the indices the quantizer came up with are kept if there are any
+    fn dither(
+        &self,
+        img: &[(u8, u8, u8, u8)],
+        dimensions: (usize, usize),
+        palette: vec::Vec<(u8, u8, u8, u8)>,
+        indices: Option<vec::Vec<u8>>,
+    ) -> Result<(vec::Vec<(u8, u8, u8, u8)>, vec::Vec<u8>), Error> {
+        let no_dithering =
+            matches!(self.dither_type, dither::DitherType::None) || self.dithering_level == 0.;
+        if let (true, Some(indices)) = (no_dithering, indices) {
+            return Ok((palette, indices));
+        }
+
+        let indices = dither::remap(
+            img,
+            dimensions,
+            &palette[..],
+            self.dither_type,
+            self.dithering_level,
+        );
+        return Ok((palette, indices));
+    }
 }
 
 /* helper method to just transform input to the appropriate output.
@@ -53,48 +266,529 @@ impl Quantizer {
 pub fn quantize_identity(
     img: vec::Vec<(u8, u8, u8, u8)>,
     dimensions: (usize, usize),
-) -> Result<(vec::Vec<(u8, u8, u8, u8)>, vec::Vec<usize>), Box<dyn error::Error>> {
+) -> Result<(vec::Vec<(u8, u8, u8, u8)>, vec::Vec<usize>), Error> {
     let mut color_map: hash_map::HashMap<(u8, u8, u8, u8), usize> = hash_map::HashMap::new();
     let mut palette_list = vec::Vec::new();
-    let mut indexed_pixels = vec::Vec::new();
-    indexed_pixels.resize(dimensions.0 * dimensions.1, 0);
+    let mut indexed_pixels = vec![0; dimensions.0 * dimensions.1];
     for (i, color) in img.into_iter().enumerate() {
-        if !color_map.contains_key(&color) {
-            palette_list.push(color.clone());
-            color_map.insert(color, palette_list.len() - 1);
+        indexed_pixels[i] = *color_map.entry(color).or_insert_with(|| {
+            palette_list.push(color);
+            return palette_list.len() - 1;
+        });
+    }
+
+    return Ok((palette_list, indexed_pixels));
+}
+
+/* Shared by the native quantizers, which only pick colors and leave alpha to this.
+ * Fully transparent pixels always get a palette entry of their own so they stay transparent, and
+ * every other entry takes the average alpha of the pixels mapped to it.
+ */
+fn quantize_with(
+    img: &[(u8, u8, u8, u8)],
+    max_color_count: usize,
+    algorithm: PaletteAlgorithm,
+) -> Result<(vec::Vec<(u8, u8, u8, u8)>, vec::Vec<u8>), Error> {
+    let has_transparent = img.iter().any(|pixel| pixel.3 == 0);
+    let max_opaque_count = max_color_count.saturating_sub(has_transparent as usize);
+    if max_opaque_count == 0 || max_color_count > 256 {
+        return Err(Error::Quantize(QuantizeError::InvalidSetting(
+            None,
+            format!("Can't quantize to {} colors", max_color_count),
+        )));
+    }
+
+    let mut histogram_indices: hash_map::HashMap<Rgb, usize> = hash_map::HashMap::new();
+    let mut histogram: Histogram = vec::Vec::new();
+    for pixel in img.iter().filter(|pixel| pixel.3 != 0) {
+        let index = *histogram_indices
+            .entry((pixel.0, pixel.1, pixel.2))
+            .or_insert_with(|| {
+                histogram.push(((pixel.0, pixel.1, pixel.2), 0));
+                return histogram.len() - 1;
+            });
+        histogram[index].1 += 1;
+    }
+
+    // no need to do any extra work, we have all the colors we need
+    let (colors, mapping) = if histogram.len() <= max_opaque_count {
+        (
+            histogram.iter().map(|(color, _)| *color).collect(),
+            (0..histogram.len()).collect(),
+        )
+    } else {
+        algorithm(&histogram, max_opaque_count)
+    };
+
+    let transparent_index = colors.len();
+    let mut alpha_sums = vec![(0usize, 0usize); colors.len()];
+    let indices = img
+        .iter()
+        .map(|pixel| {
+            if pixel.3 == 0 {
+                return transparent_index as u8;
+            }
+
+            let index = mapping[histogram_indices[&(pixel.0, pixel.1, pixel.2)]];
+            alpha_sums[index].0 += pixel.3 as usize;
+            alpha_sums[index].1 += 1;
+            return index as u8;
+        })
+        .collect();
+
+    let mut palette = colors
+        .into_iter()
+        .zip(alpha_sums)
+        .map(|(color, (alpha_sum, count))| {
+            // an entry no pixel ended up using doesn't matter, so it's just left opaque
+            let alpha = (alpha_sum + (count / 2)).checked_div(count).unwrap_or(255) as u8;
+            return (color.0, color.1, color.2, alpha);
+        })
+        .collect::<vec::Vec<_>>();
+    if has_transparent {
+        palette.push((0, 0, 0, 0));
+    }
+
+    return Ok((palette, indices));
+}
+
+// drops the lowest bits, repeating the highest ones in their place so white stays white
+fn posterize(value: u8, bits: u8) -> u8 {
+    if bits == 0 {
+        return value;
+    }
+
+    return (value & !((1u8 << bits) - 1)) | (value >> (8 - bits));
+}
+
+fn mean_squared_error(
+    img: &[(u8, u8, u8, u8)],
+    palette: &[(u8, u8, u8, u8)],
+    indices: &[u8],
+) -> f64 {
+    let mut sum = 0f64;
+    let mut count = 0usize;
+    for (pixel, &index) in img.iter().zip(indices.iter()) {
+        if pixel.3 == 0 {
+            continue;
         }
 
-        indexed_pixels[i] = *color_map.get(&color).unwrap();
+        let mapped = palette[index as usize];
+        let dr = pixel.0 as f64 - mapped.0 as f64;
+        let dg = pixel.1 as f64 - mapped.1 as f64;
+        let db = pixel.2 as f64 - mapped.2 as f64;
+        sum += ((dr * dr) + (dg * dg) + (db * db)) / 3.;
+        count += 1;
     }
 
-    return Ok((palette_list, indexed_pixels));
+    if count == 0 {
+        return 0.;
+    }
+
+    return sum / count as f64;
+}
+
+fn distance(a: Rgb, b: Rgb) -> u32 {
+    let dr = a.0 as i32 - b.0 as i32;
+    let dg = a.1 as i32 - b.1 as i32;
+    let db = a.2 as i32 - b.2 as i32;
+    return ((dr * dr) + (dg * dg) + (db * db)) as u32;
+}
+
+fn nearest(palette: &[Rgb], color: Rgb) -> usize {
+    let mut best = (0, u32::MAX);
+    for (i, &candidate) in palette.iter().enumerate() {
+        let d = distance(candidate, color);
+        if d < best.1 {
+            best = (i, d);
+        }
+    }
+
+    return best.0;
 }
 
-// pub fn quantize_scalar(
-//     img: vec::Vec<(u8, u8, u8, u8)>
-//                       )
+// the average color of the histogram entries, weighted by how many pixels have them
+fn weighted_average<'a>(entries: impl Iterator<Item = &'a (Rgb, usize)>) -> Rgb {
+    let mut sums = (0usize, 0usize, 0usize, 0usize);
+    for ((r, g, b), count) in entries {
+        sums.0 += *r as usize * count;
+        sums.1 += *g as usize * count;
+        sums.2 += *b as usize * count;
+        sums.3 += count;
+    }
+
+    if sums.3 == 0 {
+        return (0, 0, 0);
+    }
+
+    let half = sums.3 / 2;
+    return (
+        ((sums.0 + half) / sums.3) as u8,
+        ((sums.1 + half) / sums.3) as u8,
+        ((sums.2 + half) / sums.3) as u8,
+    );
+}
+
+/* Uniform quantization, where the bits of the palette index are split between the channels and
+ * every color lands in the middle of its bucket. With 256 colors that's 3 bits of red, 3 bits of
+ * green and 2 bits of blue, since the eye is least sensitive to blue.
+ */
+fn palette_scalar(
+    histogram: &Histogram,
+    max_color_count: usize,
+) -> (vec::Vec<Rgb>, vec::Vec<usize>) {
+    let total_bits = cmp::min(max_color_count.ilog2(), 24);
+    let blue_bits = total_bits / 3;
+    let red_bits = (total_bits - blue_bits) / 2;
+    let green_bits = total_bits - blue_bits - red_bits;
 
+    let bucket = |value: u8, bits: u32| -> u8 {
+        if bits == 0 {
+            return 128;
+        }
+
+        let shift = 8 - bits;
+        return ((value >> shift) << shift) | ((1u16 << shift) / 2) as u8;
+    };
+
+    let mut palette_indices: hash_map::HashMap<Rgb, usize> = hash_map::HashMap::new();
+    let mut palette = vec::Vec::new();
+    let mapping = histogram
+        .iter()
+        .map(|((r, g, b), _)| {
+            let bucketed = (
+                bucket(*r, red_bits),
+                bucket(*g, green_bits),
+                bucket(*b, blue_bits),
+            );
+            return *palette_indices.entry(bucketed).or_insert_with(|| {
+                palette.push(bucketed);
+                return palette.len() - 1;
+            });
+        })
+        .collect();
+
+    return (palette, mapping);
+}
+
+// keeps the most common colors, mapping everything else to whichever of them is closest
+fn palette_populosity(
+    histogram: &Histogram,
+    max_color_count: usize,
+) -> (vec::Vec<Rgb>, vec::Vec<usize>) {
+    let mut sorted = histogram.clone();
+    sorted.sort_by_key(|&(_, count)| cmp::Reverse(count));
+
+    let palette = sorted
+        .into_iter()
+        .take(max_color_count)
+        .map(|(color, _)| color)
+        .collect::<vec::Vec<_>>();
+    let mapping = histogram
+        .iter()
+        .map(|(color, _)| nearest(&palette[..], *color))
+        .collect();
+
+    return (palette, mapping);
+}
+
+fn channel(color: Rgb, i: usize) -> u8 {
+    return match i {
+        0 => color.0,
+        1 => color.1,
+        _ => color.2,
+    };
+}
+
+// the widest channel of the box and how wide it is
+fn widest_channel(histogram: &Histogram, entries: &[usize]) -> (usize, u8) {
+    let mut widest = (0, 0);
+    for i in 0..3 {
+        let values = entries.iter().map(|&e| channel(histogram[e].0, i));
+        let min = values.clone().min().unwrap_or(0);
+        let max = values.max().unwrap_or(0);
+        if max - min > widest.1 {
+            widest = (i, max - min);
+        }
+    }
+
+    return widest;
+}
+
+/* Heckbert's median cut. All the colors start in a single box, and the box with the widest channel
+ * keeps being split in two at the median pixel along that channel until there are enough boxes.
+ * Each box then becomes the average of its colors.
+ */
+fn palette_median_cut(
+    histogram: &Histogram,
+    max_color_count: usize,
+) -> (vec::Vec<Rgb>, vec::Vec<usize>) {
+    let mut boxes: vec::Vec<vec::Vec<usize>> = vec![(0..histogram.len()).collect()];
+
+    while boxes.len() < max_color_count {
+        let Some((box_index, (widest, _))) = boxes
+            .iter()
+            .map(|entries| widest_channel(histogram, &entries[..]))
+            .enumerate()
+            .filter(|(_, (_, width))| *width > 0)
+            .max_by_key(|(_, (_, width))| *width)
+        else {
+            // every box is a single color already
+            break;
+        };
+
+        let mut entries = boxes.swap_remove(box_index);
+        entries.sort_by_key(|&e| channel(histogram[e].0, widest));
+
+        // split at the median pixel rather than the median color, so busy colors get more boxes
+        let total = entries.iter().map(|&e| histogram[e].1).sum::<usize>();
+        let mut seen = 0;
+        let mut split = 1;
+        for (i, &e) in entries.iter().enumerate() {
+            seen += histogram[e].1;
+            if seen * 2 >= total {
+                split = i + 1;
+                break;
+            }
+        }
+        let split = split.clamp(1, entries.len() - 1);
+
+        let upper = entries.split_off(split);
+        boxes.push(entries);
+        boxes.push(upper);
+    }
+
+    let mut mapping = vec![0; histogram.len()];
+    let palette = boxes
+        .iter()
+        .enumerate()
+        .map(|(i, entries)| {
+            for &e in entries {
+                mapping[e] = i;
+            }
+
+            return weighted_average(entries.iter().map(|&e| &histogram[e]));
+        })
+        .collect();
+
+    return (palette, mapping);
+}
+
+struct OctreeNode {
+    children: [Option<usize>; 8],
+    pixel_count: usize,
+    sums: (usize, usize, usize),
+    is_leaf: bool,
+}
+
+impl OctreeNode {
+    fn new(is_leaf: bool) -> Self {
+        return OctreeNode {
+            children: [None; 8],
+            pixel_count: 0,
+            sums: (0, 0, 0),
+            is_leaf,
+        };
+    }
+}
+
+// which child a color goes into at the given level, from one bit of each channel
+fn octree_child(color: Rgb, level: usize) -> usize {
+    let shift = 7 - level;
+    return ((((color.0 >> shift) & 1) << 2)
+        | (((color.1 >> shift) & 1) << 1)
+        | ((color.2 >> shift) & 1)) as usize;
+}
+
+/* Gervautz and Purgathofer's octree. Every color is a path down the tree picking a child by one bit
+ * of each channel per level, and while there are too many leaves, the least used node on the
+ * deepest level with children has them merged into it.
+ */
+fn palette_octree(
+    histogram: &Histogram,
+    max_color_count: usize,
+) -> (vec::Vec<Rgb>, vec::Vec<usize>) {
+    const DEPTH: usize = 8;
+
+    let mut nodes = vec![OctreeNode::new(false)];
+    let mut levels: vec::Vec<vec::Vec<usize>> = vec![vec::Vec::new(); DEPTH];
+    levels[0].push(0);
+    let mut leaf_count = 0;
+
+    for &(color, count) in histogram.iter() {
+        let mut node = 0;
+        for level in 0..DEPTH {
+            let child = octree_child(color, level);
+            node = match nodes[node].children[child] {
+                Some(existing) => existing,
+                None => {
+                    let is_leaf = level + 1 == DEPTH;
+                    nodes.push(OctreeNode::new(is_leaf));
+                    let created = nodes.len() - 1;
+                    nodes[node].children[child] = Some(created);
+                    if is_leaf {
+                        leaf_count += 1;
+                    } else {
+                        levels[level + 1].push(created);
+                    }
+                    created
+                }
+            };
+        }
+
+        let leaf = &mut nodes[node];
+        leaf.pixel_count += count;
+        leaf.sums.0 += color.0 as usize * count;
+        leaf.sums.1 += color.1 as usize * count;
+        leaf.sums.2 += color.2 as usize * count;
+    }
+
+    while leaf_count > max_color_count {
+        let Some(level) = levels.iter().rposition(|nodes| !nodes.is_empty()) else {
+            break;
+        };
+
+        // the counts are only filled in for leaves, so a node's usage is the sum of its children
+        let usage = |node: usize| -> usize {
+            return nodes[node]
+                .children
+                .iter()
+                .flatten()
+                .map(|&child| nodes[child].pixel_count)
+                .sum();
+        };
+        let (position, _) = levels[level]
+            .iter()
+            .enumerate()
+            .min_by_key(|(_, node)| usage(**node))
+            .unwrap();
+        let node = levels[level].swap_remove(position);
+
+        let children = nodes[node]
+            .children
+            .iter()
+            .flatten()
+            .copied()
+            .collect::<vec::Vec<_>>();
+        for &child in children.iter() {
+            let (pixel_count, sums) = (nodes[child].pixel_count, nodes[child].sums);
+            let merged = &mut nodes[node];
+            merged.pixel_count += pixel_count;
+            merged.sums.0 += sums.0;
+            merged.sums.1 += sums.1;
+            merged.sums.2 += sums.2;
+        }
+
+        nodes[node].children = [None; 8];
+        nodes[node].is_leaf = true;
+        leaf_count = leaf_count + 1 - children.len();
+    }
+
+    let mut palette = vec::Vec::new();
+    let mut leaf_indices: hash_map::HashMap<usize, usize> = hash_map::HashMap::new();
+    let mapping = histogram
+        .iter()
+        .map(|&(color, _)| {
+            let mut node = 0;
+            let mut level = 0;
+            while !nodes[node].is_leaf {
+                node = nodes[node].children[octree_child(color, level)]
+                    .expect("every color has a path down the tree");
+                level += 1;
+            }
+
+            return *leaf_indices.entry(node).or_insert_with(|| {
+                let leaf = &nodes[node];
+                let half = leaf.pixel_count / 2;
+                palette.push((
+                    ((leaf.sums.0 + half) / leaf.pixel_count) as u8,
+                    ((leaf.sums.1 + half) / leaf.pixel_count) as u8,
+                    ((leaf.sums.2 + half) / leaf.pixel_count) as u8,
+                ));
+                return palette.len() - 1;
+            });
+        })
+        .collect();
+
+    return (palette, mapping);
+}
+
+/* Lloyd's k-means, starting from the median cut palette. Every color is assigned to its nearest
+ * center and the centers are moved to the average of their colors until nothing changes anymore.
+ */
+fn palette_kmeans(
+    histogram: &Histogram,
+    max_color_count: usize,
+) -> (vec::Vec<Rgb>, vec::Vec<usize>) {
+    const MAX_ITERATIONS: usize = 16;
+
+    let (mut palette, mut mapping) = palette_median_cut(histogram, max_color_count);
+
+    for _ in 0..MAX_ITERATIONS {
+        let mut clusters: vec::Vec<vec::Vec<usize>> = vec![vec::Vec::new(); palette.len()];
+        for (i, &index) in mapping.iter().enumerate() {
+            clusters[index].push(i);
+        }
+
+        // a center nobody picked stays where it is
+        for (center, cluster) in palette.iter_mut().zip(clusters.iter()) {
+            if !cluster.is_empty() {
+                *center = weighted_average(cluster.iter().map(|&e| &histogram[e]));
+            }
+        }
+
+        let reassigned = histogram
+            .iter()
+            .map(|(color, _)| nearest(&palette[..], *color))
+            .collect::<vec::Vec<_>>();
+        if reassigned == mapping {
+            break;
+        }
+
+        mapping = reassigned;
+    }
+
+    return (palette, mapping);
+}
+
+// also returns the quality libimagequant thinks it achieved
 pub fn quantize_image_quant(
     img: vec::Vec<(u8, u8, u8, u8)>,
     dimensions: (usize, usize),
-) -> Result<(vec::Vec<(u8, u8, u8, u8)>, vec::Vec<u8>), Box<dyn error::Error>> {
+    settings: &Quantizer,
+    dithering_level: f32,
+) -> Result<(vec::Vec<(u8, u8, u8, u8)>, vec::Vec<u8>, Option<u8>), Error> {
+    let failed = |e: imagequant::Error| {
+        return Error::Quantize(QuantizeError::Failed(
+            Some(Box::new(e)),
+            "libimagequant couldn't quantize the image".to_owned(),
+        ));
+    };
+
     let mut liq = imagequant::new();
-    liq.set_speed(5)?;
-    liq.set_quality(0, 100)?;
+    liq.set_speed(settings.speed).map_err(failed)?;
+    liq.set_quality(settings.min_quality, settings.max_quality)
+        .map_err(failed)?;
+    liq.set_max_colors(settings.max_color_count as u32)
+        .map_err(failed)?;
+    liq.set_min_posterization(settings.posterization)
+        .map_err(failed)?;
 
-    let ref mut img = liq.new_image(
-        img.into_iter().map(|e| e.into()).collect::<vec::Vec<_>>(),
-        dimensions.0,
-        dimensions.1,
-        0.0,
-    )?;
+    let img = &mut liq
+        .new_image(
+            img.into_iter().map(|e| e.into()).collect::<vec::Vec<_>>(),
+            dimensions.0,
+            dimensions.1,
+            0.0,
+        )
+        .map_err(failed)?;
 
-    let mut res = liq.quantize(img)?;
-    res.set_dithering_level(1.0)?;
+    let mut res = liq.quantize(img).map_err(failed)?;
+    res.set_dithering_level(dithering_level).map_err(failed)?;
 
-    let (palette, pixels) = res.remapped(img).unwrap();
+    let (palette, pixels) = res.remapped(img).map_err(failed)?;
 
-    Ok((
+    return Ok((
         palette
             .into_iter()
             .map(|pixel| {
@@ -102,5 +796,90 @@ pub fn quantize_image_quant(
             })
             .collect(),
         pixels,
-    ))
+        res.quantization_quality(),
+    ));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Quantizer, QuantizerType};
+
+    // every color on a 32x32 grid of red and green, with the first row transparent
+    fn image() -> Vec<(u8, u8, u8, u8)> {
+        return (0..32u8)
+            .flat_map(|y| {
+                return (0..32u8).map(move |x| {
+                    if y == 0 {
+                        return (0, 0, 0, 0);
+                    }
+
+                    return (x * 8, y * 8, 128, 255);
+                });
+            })
+            .collect();
+    }
+
+    #[test]
+    fn test_native_quantizers() {
+        let img = image();
+        for quantizer_type in [
+            QuantizerType::SCALAR,
+            QuantizerType::POPULOSITY,
+            QuantizerType::MEDIANCUT,
+            QuantizerType::OCTREE,
+            QuantizerType::KMEANS,
+        ] {
+            let (palette, indices) = Quantizer::new(16, quantizer_type)
+                .run(img.clone(), (32, 32))
+                .unwrap();
+
+            assert!(
+                palette.len() <= 16,
+                "{} had {} colors",
+                quantizer_type,
+                palette.len()
+            );
+            assert_eq!(indices.len(), img.len());
+            assert!(indices.iter().all(|&i| (i as usize) < palette.len()));
+
+            // transparent pixels keep a fully transparent entry of their own
+            assert_eq!(palette[indices[0] as usize], (0, 0, 0, 0));
+            assert_eq!(palette[indices[img.len() - 1] as usize].3, 255);
+        }
+    }
+
+    #[test]
+    fn test_identity_too_many_colors() {
+        assert!(Quantizer::new(16, QuantizerType::IDENTITY)
+            .run(image(), (32, 32))
+            .is_err());
+    }
+
+    #[test]
+    fn test_settings_and_report() {
+        let mut quantizer = Quantizer::new(8, QuantizerType::IMAGEQUANT);
+        quantizer.posterization = 2;
+        let (palette, _, report) = quantizer.run_with_report(image(), (32, 32)).unwrap();
+        assert!(palette.len() <= 8);
+        assert!(palette
+            .iter()
+            .filter(|c| c.3 == 255)
+            .all(|c| (c.0 >> 6) == (c.0 & 0b11) && (c.1 >> 6) == (c.1 & 0b11)));
+        assert!(report.quality.is_some());
+
+        // more colors can only get closer to the original
+        quantizer.max_color_count = 64;
+        let (_, _, better) = quantizer.run_with_report(image(), (32, 32)).unwrap();
+        assert!(better.error < report.error);
+
+        // the identity quantizer is lossless, and doesn't estimate a quality
+        let (_, _, report) = Quantizer::new(256, QuantizerType::IDENTITY)
+            .run_with_report(image()[..32 * 4].to_vec(), (32, 4))
+            .unwrap();
+        assert_eq!(report.quality, None);
+        assert_eq!(report.error, 0.);
+
+        quantizer.speed = 11;
+        assert!(quantizer.run(image(), (32, 32)).is_err());
+    }
 }
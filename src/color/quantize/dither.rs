@@ -0,0 +1,404 @@
+/* Maps pixels onto a palette that was already picked, spreading out the difference between the
+ * pixel and the palette color it's mapped to so gradients don't turn into bands.
+ *
+ * Error diffusion pushes the error of every pixel onto the neighbours that haven't been mapped yet,
+ * while ordered dithering nudges every pixel by a fixed threshold map before picking the closest
+ * color, which keeps the pattern from crawling between the frames of an animation.
+ *
+ * Fully transparent pixels are always mapped to a transparent palette entry and never spread any
+ * error, since they don't have a color to begin with.
+ */
+
+use std::sync;
+use std::vec;
+
+use clap::{builder::PossibleValue, ValueEnum};
+
+use crate::commandline;
+
+commandline::define_cli_enum!(DitherType, {
+    None: ("none", "Maps every pixel to the closest color, which keeps pixel art crisp"),
+    FloydSteinberg: ("floyd_steinberg", "Error diffusion over the 4 next pixels"),
+    Atkinson: ("atkinson", "Error diffusion that only spreads 3/4 of the error, keeping more contrast"),
+    Sierra: ("sierra", "Error diffusion over the 10 next pixels, smoother than Floyd-Steinberg"),
+    Bayer2: ("bayer2", "Ordered dithering with a 2x2 Bayer matrix"),
+    Bayer4: ("bayer4", "Ordered dithering with a 4x4 Bayer matrix"),
+    Bayer8: ("bayer8", "Ordered dithering with an 8x8 Bayer matrix"),
+    BlueNoise: ("blue_noise", "Ordered dithering with a blue noise threshold map, without the Bayer cross hatching"),
+});
+
+type Pixel = (u8, u8, u8, u8);
+
+// (x offset, y offset, weight) of the neighbours the error is spread onto, and what the weights
+// are out of
+type Kernel = (&'static [(isize, usize, f32)], f32);
+
+const FLOYD_STEINBERG: Kernel = (&[(1, 0, 7.), (-1, 1, 3.), (0, 1, 5.), (1, 1, 1.)], 16.);
+
+const ATKINSON: Kernel = (
+    &[
+        (1, 0, 1.),
+        (2, 0, 1.),
+        (-1, 1, 1.),
+        (0, 1, 1.),
+        (1, 1, 1.),
+        (0, 2, 1.),
+    ],
+    8.,
+);
+
+const SIERRA: Kernel = (
+    &[
+        (1, 0, 5.),
+        (2, 0, 3.),
+        (-2, 1, 2.),
+        (-1, 1, 4.),
+        (0, 1, 5.),
+        (1, 1, 4.),
+        (2, 1, 2.),
+        (-1, 2, 2.),
+        (0, 2, 3.),
+        (1, 2, 2.),
+    ],
+    32.,
+);
+
+const BLUE_NOISE_SIZE: usize = 32;
+
+pub fn remap(
+    img: &[Pixel],
+    dimensions: (usize, usize),
+    palette: &[Pixel],
+    dither_type: DitherType,
+    level: f32,
+) -> vec::Vec<u8> {
+    let transparent_index = palette.iter().position(|c| c.3 == 0).unwrap_or(0);
+
+    // pixels that aren't transparent should never end up transparent
+    let mut candidates = (0..palette.len())
+        .filter(|&i| palette[i].3 != 0)
+        .collect::<vec::Vec<_>>();
+    if candidates.is_empty() {
+        candidates = (0..palette.len()).collect();
+    }
+
+    let nearest = |color: (f32, f32, f32)| -> usize {
+        let mut best = (0, f32::MAX);
+        for &i in candidates.iter() {
+            let dr = palette[i].0 as f32 - color.0;
+            let dg = palette[i].1 as f32 - color.1;
+            let db = palette[i].2 as f32 - color.2;
+            let d = (dr * dr) + (dg * dg) + (db * db);
+            if d < best.1 {
+                best = (i, d);
+            }
+        }
+
+        return best.0;
+    };
+
+    let diffuse_with = |kernel: Kernel| {
+        return diffuse(
+            img,
+            dimensions,
+            palette,
+            transparent_index,
+            nearest,
+            kernel,
+            level,
+        );
+    };
+    let ordered_with = |threshold_map: &[usize]| {
+        return ordered(
+            img,
+            dimensions,
+            threshold_map,
+            candidates.len(),
+            transparent_index,
+            nearest,
+            level,
+        );
+    };
+
+    return match dither_type {
+        DitherType::None => img
+            .iter()
+            .map(|pixel| {
+                if pixel.3 == 0 {
+                    return transparent_index as u8;
+                }
+
+                return nearest((pixel.0 as f32, pixel.1 as f32, pixel.2 as f32)) as u8;
+            })
+            .collect(),
+        DitherType::FloydSteinberg => diffuse_with(FLOYD_STEINBERG),
+        DitherType::Atkinson => diffuse_with(ATKINSON),
+        DitherType::Sierra => diffuse_with(SIERRA),
+        DitherType::Bayer2 => ordered_with(&bayer(2)[..]),
+        DitherType::Bayer4 => ordered_with(&bayer(4)[..]),
+        DitherType::Bayer8 => ordered_with(&bayer(8)[..]),
+        DitherType::BlueNoise => ordered_with(&blue_noise()[..]),
+    };
+}
+
+fn diffuse<F>(
+    img: &[Pixel],
+    (width, height): (usize, usize),
+    palette: &[Pixel],
+    transparent_index: usize,
+    nearest: F,
+    (kernel, divisor): Kernel,
+    level: f32,
+) -> vec::Vec<u8>
+where
+    F: Fn((f32, f32, f32)) -> usize,
+{
+    let mut errors = vec![(0f32, 0f32, 0f32); width * height];
+    let mut indices = vec![0u8; width * height];
+
+    for y in 0..height {
+        for x in 0..width {
+            let i = (y * width) + x;
+            let pixel = img[i];
+            if pixel.3 == 0 {
+                indices[i] = transparent_index as u8;
+                continue;
+            }
+
+            let wanted = (
+                (pixel.0 as f32 + errors[i].0).clamp(0., 255.),
+                (pixel.1 as f32 + errors[i].1).clamp(0., 255.),
+                (pixel.2 as f32 + errors[i].2).clamp(0., 255.),
+            );
+            let index = nearest(wanted);
+            indices[i] = index as u8;
+
+            let error = (
+                wanted.0 - palette[index].0 as f32,
+                wanted.1 - palette[index].1 as f32,
+                wanted.2 - palette[index].2 as f32,
+            );
+            for &(dx, dy, weight) in kernel {
+                let (nx, ny) = (x as isize + dx, y + dy);
+                if nx < 0 || nx as usize >= width || ny >= height {
+                    continue;
+                }
+
+                let n = (ny * width) + nx as usize;
+                let share = (weight / divisor) * level;
+                errors[n].0 += error.0 * share;
+                errors[n].1 += error.1 * share;
+                errors[n].2 += error.2 * share;
+            }
+        }
+    }
+
+    return indices;
+}
+
+// threshold maps are squares holding the ranks 0..size*size, which are spread evenly around 0
+fn ordered<F>(
+    img: &[Pixel],
+    (width, _): (usize, usize),
+    threshold_map: &[usize],
+    color_count: usize,
+    transparent_index: usize,
+    nearest: F,
+    level: f32,
+) -> vec::Vec<u8>
+where
+    F: Fn((f32, f32, f32)) -> usize,
+{
+    // roughly the distance between neighbouring palette colors if they were spread out evenly
+    let spread = (255. / (color_count as f32).cbrt()) * level;
+    let size = threshold_map.len().isqrt();
+    let levels = threshold_map.len() as f32;
+
+    return img
+        .iter()
+        .enumerate()
+        .map(|(i, pixel)| {
+            if pixel.3 == 0 {
+                return transparent_index as u8;
+            }
+
+            let (x, y) = (i % width, i / width);
+            let rank = threshold_map[((y % size) * size) + (x % size)] as f32;
+            let offset = (((rank + 0.5) / levels) - 0.5) * spread;
+
+            return nearest((
+                (pixel.0 as f32 + offset).clamp(0., 255.),
+                (pixel.1 as f32 + offset).clamp(0., 255.),
+                (pixel.2 as f32 + offset).clamp(0., 255.),
+            )) as u8;
+        })
+        .collect();
+}
+
+// each size is built from the one half its size, starting from [[0, 2], [3, 1]]
+fn bayer(size: usize) -> vec::Vec<usize> {
+    if size <= 1 {
+        return vec![0];
+    }
+
+    let half = size / 2;
+    let smaller = bayer(half);
+    let mut matrix = vec![0; size * size];
+    for y in 0..size {
+        for x in 0..size {
+            let quadrant = match (x / half, y / half) {
+                (0, 0) => 0,
+                (1, 0) => 2,
+                (0, _) => 3,
+                _ => 1,
+            };
+            matrix[(y * size) + x] = (4 * smaller[((y % half) * half) + (x % half)]) + quadrant;
+        }
+    }
+
+    return matrix;
+}
+
+/* Ulichney's void-and-cluster. Starting from a few random points, points are moved from their
+ * tightest cluster into the largest void until that stops changing anything. Then the points are
+ * ranked by taking them away one cluster at a time, and the rest by filling in the voids one at a
+ * time, which ends up with neighbouring ranks far apart from each other.
+ *
+ * It only depends on the size, so it's only generated once.
+ */
+fn blue_noise() -> &'static vec::Vec<usize> {
+    static BLUE_NOISE: sync::OnceLock<vec::Vec<usize>> = sync::OnceLock::new();
+
+    return BLUE_NOISE.get_or_init(|| {
+        const SIZE: usize = BLUE_NOISE_SIZE;
+        const SIGMA: f32 = 1.5;
+
+        // the energy a point adds at every offset, wrapping around the edges so the map tiles
+        let mut falloff = vec![0f32; SIZE * SIZE];
+        for dy in 0..SIZE {
+            for dx in 0..SIZE {
+                let wx = dx.min(SIZE - dx) as f32;
+                let wy = dy.min(SIZE - dy) as f32;
+                falloff[(dy * SIZE) + dx] = (-((wx * wx) + (wy * wy)) / (2. * SIGMA * SIGMA)).exp();
+            }
+        }
+
+        let mut points = vec![false; SIZE * SIZE];
+        let mut energy = vec![0f32; SIZE * SIZE];
+        let toggle = |points: &mut vec::Vec<bool>, energy: &mut vec::Vec<f32>, p: usize| {
+            points[p] = !points[p];
+            let sign = if points[p] { 1. } else { -1. };
+            let (px, py) = (p % SIZE, p / SIZE);
+            for y in 0..SIZE {
+                for x in 0..SIZE {
+                    let dx = (x + SIZE - px) % SIZE;
+                    let dy = (y + SIZE - py) % SIZE;
+                    energy[(y * SIZE) + x] += sign * falloff[(dy * SIZE) + dx];
+                }
+            }
+        };
+        let tightest_cluster = |points: &vec::Vec<bool>, energy: &vec::Vec<f32>| -> usize {
+            return (0..SIZE * SIZE)
+                .filter(|&p| points[p])
+                .max_by(|&a, &b| energy[a].total_cmp(&energy[b]))
+                .unwrap();
+        };
+        let largest_void = |points: &vec::Vec<bool>, energy: &vec::Vec<f32>| -> usize {
+            return (0..SIZE * SIZE)
+                .filter(|&p| !points[p])
+                .min_by(|&a, &b| energy[a].total_cmp(&energy[b]))
+                .unwrap();
+        };
+
+        // a fixed seed, so the map is the same every time
+        let mut seed = 0x2545_f491_u32;
+        let initial_count = (SIZE * SIZE) / 10;
+        let mut placed = 0;
+        while placed < initial_count {
+            seed ^= seed << 13;
+            seed ^= seed >> 17;
+            seed ^= seed << 5;
+            let p = seed as usize % (SIZE * SIZE);
+            if !points[p] {
+                toggle(&mut points, &mut energy, p);
+                placed += 1;
+            }
+        }
+
+        loop {
+            let cluster = tightest_cluster(&points, &energy);
+            toggle(&mut points, &mut energy, cluster);
+            let void = largest_void(&points, &energy);
+            toggle(&mut points, &mut energy, void);
+            if void == cluster {
+                break;
+            }
+        }
+
+        let mut ranks = vec![0; SIZE * SIZE];
+
+        let (mut removing, mut removing_energy) = (points.clone(), energy.clone());
+        for rank in (0..initial_count).rev() {
+            let cluster = tightest_cluster(&removing, &removing_energy);
+            toggle(&mut removing, &mut removing_energy, cluster);
+            ranks[cluster] = rank;
+        }
+
+        for rank in initial_count..(SIZE * SIZE) {
+            let void = largest_void(&points, &energy);
+            toggle(&mut points, &mut energy, void);
+            ranks[void] = rank;
+        }
+
+        return ranks;
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{bayer, blue_noise, remap, DitherType};
+
+    #[test]
+    fn test_threshold_maps() {
+        assert_eq!(bayer(2), vec![0, 2, 3, 1]);
+
+        // every rank shows up exactly once
+        for map in [bayer(8), blue_noise().clone()] {
+            let mut sorted = map.clone();
+            sorted.sort();
+            assert_eq!(sorted, (0..map.len()).collect::<Vec<_>>());
+        }
+    }
+
+    #[test]
+    fn test_remap_gray() {
+        let palette = [(0, 0, 0, 255), (255, 255, 255, 255), (0, 0, 0, 0)];
+        let mut img = vec![(128, 128, 128, 255); 64];
+        img[0] = (0, 0, 0, 0);
+
+        for dither_type in [
+            DitherType::FloydSteinberg,
+            DitherType::Atkinson,
+            DitherType::Sierra,
+            DitherType::Bayer2,
+            DitherType::Bayer4,
+            DitherType::Bayer8,
+            DitherType::BlueNoise,
+        ] {
+            let indices = remap(&img[..], (8, 8), &palette[..], dither_type, 1.);
+            assert_eq!(indices[0], 2);
+
+            // mid gray ends up as a mix of black and white
+            let white = indices.iter().filter(|&&i| i == 1).count();
+            assert!(
+                (20..44).contains(&white),
+                "{} had {} white pixels",
+                dither_type,
+                white
+            );
+        }
+
+        let indices = remap(&img[..], (8, 8), &palette[..], DitherType::None, 1.);
+        assert!(indices[1..].iter().all(|&i| i == indices[1]));
+    }
+}
@@ -0,0 +1,108 @@
+/* How partially transparent pixels are dealt with
+ *
+ * GIFs only have fully transparent or fully opaque pixels, while APNG and WebP can store any alpha.
+ * When alpha is kept, only fully transparent pixels are treated specially and everything else goes
+ * through as is. Otherwise every pixel is flattened: the ones with less alpha than the threshold
+ * are dropped to fully transparent, and the rest become opaque, either composited onto the matte
+ * color or just with their alpha thrown away.
+ */
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Transparency {
+    // the alpha a pixel needs to stay visible when flattened
+    pub threshold: u8,
+    // the color semi-transparent pixels get composited onto when flattened
+    pub matte: Option<[u8; 3]>,
+    // keeps partial alpha instead of flattening, for outputs that can store it
+    pub preserve_alpha: bool,
+}
+
+impl Default for Transparency {
+    // only fully opaque pixels stay visible
+    fn default() -> Self {
+        return Transparency {
+            threshold: 255,
+            matte: None,
+            preserve_alpha: false,
+        };
+    }
+}
+
+impl Transparency {
+    // what a pixel becomes for outputs that can't store partial alpha
+    pub fn flatten(&self, [r, g, b, a]: [u8; 4]) -> [u8; 4] {
+        if a == 255 {
+            return [r, g, b, a];
+        }
+
+        if a == 0 || a < self.threshold {
+            return [0, 0, 0, 0];
+        }
+
+        return match self.matte {
+            Some(matte) => {
+                let over = |top: u8, bottom: u8| {
+                    let (top, bottom, a) = (top as u32, bottom as u32, a as u32);
+                    return (((top * a) + (bottom * (255 - a)) + 127) / 255) as u8;
+                };
+
+                [over(r, matte[0]), over(g, matte[1]), over(b, matte[2]), 255]
+            }
+            None => [r, g, b, 255],
+        };
+    }
+
+    // prepares RGBA pixels for quantizing, where fully transparent pixels are all made the same so
+    // they share a single palette entry
+    pub fn apply(&self, rgba: &mut [u8]) {
+        for pixel in rgba.chunks_mut(4) {
+            if self.preserve_alpha {
+                if pixel[3] == 0 {
+                    pixel.fill(0);
+                }
+                continue;
+            }
+
+            let flattened = self.flatten([pixel[0], pixel[1], pixel[2], pixel[3]]);
+            pixel.copy_from_slice(&flattened);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Transparency;
+
+    #[test]
+    fn test_flatten() {
+        let half = [200, 100, 0, 128];
+
+        // by default anything that isn't fully opaque is dropped
+        let transparency = Transparency::default();
+        assert_eq!(transparency.flatten(half), [0, 0, 0, 0]);
+        assert_eq!(transparency.flatten([1, 2, 3, 255]), [1, 2, 3, 255]);
+
+        let transparency = Transparency {
+            threshold: 128,
+            ..Transparency::default()
+        };
+        assert_eq!(transparency.flatten(half), [200, 100, 0, 255]);
+        assert_eq!(transparency.flatten([200, 100, 0, 127]), [0, 0, 0, 0]);
+
+        let transparency = Transparency {
+            threshold: 1,
+            matte: Some([0, 0, 255]),
+            ..Transparency::default()
+        };
+        assert_eq!(transparency.flatten(half), [100, 50, 127, 255]);
+        assert_eq!(transparency.flatten([9, 9, 9, 0]), [0, 0, 0, 0]);
+
+        let mut rgba = [200, 100, 0, 128, 9, 9, 9, 0];
+        Transparency {
+            preserve_alpha: true,
+            ..Transparency::default()
+        }
+        .apply(&mut rgba);
+        assert_eq!(rgba, [200, 100, 0, 128, 0, 0, 0, 0]);
+    }
+}
@@ -2,6 +2,7 @@ use std::error;
 use std::vec;
 
 use crate::color;
+use crate::Error;
 
 macro_rules! define_cli_enum {
     ($enum_name:ident, { $($enum_val:ident : ($enum_val_name:literal, $enum_help:literal)),* $(,)? }) => {
@@ -65,54 +66,137 @@ crate::error_utils::define_error!(
     }
 );
 
-pub fn get_colors<C>(matches: &clap::ArgMatches) -> Result<vec::Vec<C>, Box<dyn error::Error>>
+// parses hex colors without the leading # into a color list usable by GradientOptions
+pub fn parse_colors<S>(
+    color_strings: impl IntoIterator<Item = S>,
+) -> Result<vec::Vec<color::ColorType>, Error>
 where
-    C: color::Color,
+    S: AsRef<str>,
 {
-    let color_strings = matches.get_many::<String>("colors").unwrap();
-    let mut color_vec: vec::Vec<C> = vec::Vec::new();
+    let mut color_vec: vec::Vec<color::ColorType> = vec::Vec::new();
     for color_string in color_strings {
-        if color_string.len() != 6 {
-            return Err(Box::new(CommandlineError::InvalidValue(
-                None,
-                format!("Invalid color format {}", &color_string),
-            )));
-        }
+        let [r, g, b] = parse_rgb(color_string.as_ref())?;
 
-        match color::from_hex::<C>(&color_string) {
-            Ok(c) => color_vec.push(c),
-            Err(e) => {
-                return Err(Box::new(CommandlineError::InvalidValue(
-                    Some(Box::new(e)),
-                    format!("Could not parse {} as color", color_string).into(),
-                )))
-            }
-        }
+        // expects values in (0, 1)
+        color_vec.push(color::ColorType::new(
+            r as color::ScalarType / 255.0,
+            g as color::ScalarType / 255.0,
+            b as color::ScalarType / 255.0,
+            1.0,
+        ));
     }
 
     return Ok(color_vec);
 }
 
-pub fn get_gradient<C>(
+// parses a single hex color without the leading # into its 8 bit channels
+pub fn parse_rgb(color_string: &str) -> Result<[u8; 3], Error> {
+    let invalid = |source: Option<Box<dyn error::Error + Send + Sync>>| {
+        return Error::Commandline(CommandlineError::InvalidValue(
+            source,
+            format!("Could not parse {} as color", color_string),
+        ));
+    };
+
+    // from_str_radix alone would also take a sign, and slicing needs single byte characters
+    if color_string.len() != 6 || !color_string.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return Err(invalid(None));
+    }
+
+    let mut rgb = [0u8; 3];
+    for (i, channel) in rgb.iter_mut().enumerate() {
+        *channel = u8::from_str_radix(&color_string[i * 2..(i * 2) + 2], 16)
+            .map_err(|e| invalid(Some(Box::new(e))))?;
+    }
+
+    return Ok(rgb);
+}
+
+// parses either a number of repeats or "infinite"
+pub fn parse_repeat(repeat: &str) -> Result<gif::Repeat, Error> {
+    if repeat.eq_ignore_ascii_case("infinite") {
+        return Ok(gif::Repeat::Infinite);
+    }
+
+    return match repeat.parse::<u16>() {
+        Ok(count) => Ok(gif::Repeat::Finite(count)),
+        Err(e) => Err(Error::Commandline(CommandlineError::InvalidValue(
+            Some(Box::new(e)),
+            format!(
+                "Expected a number up to {} or infinite, but got {}",
+                u16::MAX,
+                repeat
+            ),
+        ))),
+    };
+}
+
+// parses dimensions written as WIDTHxHEIGHT, where neither can be 0
+pub fn parse_size(size: &str) -> Result<(u16, u16), Error> {
+    let invalid = |source: Option<Box<dyn error::Error + Send + Sync>>| {
+        return Error::Commandline(CommandlineError::InvalidValue(
+            source,
+            format!("Expected a size like 128x128, but got {}", size),
+        ));
+    };
+
+    let Some((width, height)) = size.split_once(['x', 'X']) else {
+        return Err(invalid(None));
+    };
+    let parse = |length: &str| {
+        return match length.parse::<u16>() {
+            Ok(0) => Err(invalid(None)),
+            Ok(length) => Ok(length),
+            Err(e) => Err(invalid(Some(Box::new(e)))),
+        };
+    };
+
+    return Ok((parse(width)?, parse(height)?));
+}
+
+// parses a rectangle written as WIDTHxHEIGHT+X+Y into its origin and dimensions, where the offsets
+// can be left out to crop from the top left
+pub fn parse_crop(crop: &str) -> Result<((u16, u16), (u16, u16)), Error> {
+    let mut parts = crop.split('+');
+    let dimensions = parse_size(parts.next().unwrap_or_default())?;
+
+    let mut offsets = [0u16; 2];
+    for offset in &mut offsets {
+        let Some(part) = parts.next() else {
+            break;
+        };
+
+        *offset = part.parse::<u16>().map_err(|e| {
+            return Error::Commandline(CommandlineError::InvalidValue(
+                Some(Box::new(e)),
+                format!("Expected a rectangle like 64x64+16+0, but got {}", crop),
+            ));
+        })?;
+    }
+
+    if parts.next().is_some() {
+        return Err(Error::Commandline(CommandlineError::InvalidValue(
+            None,
+            format!("Expected a rectangle like 64x64+16+0, but got {}", crop),
+        )));
+    }
+
+    return Ok(((offsets[0], offsets[1]), dimensions));
+}
+
+pub fn get_gradient_options(
     matches: &clap::ArgMatches,
-    colors: vec::Vec<C>,
-    frames_len: usize,
-    loop_count: usize,
-) -> vec::Vec<C>
-where
-    C: color::Color + palette::Mix<Scalar = color::ScalarType> + Clone,
-    palette::rgb::Rgb:
-        palette::convert::FromColorUnclamped<<C as palette::WithAlpha<color::ScalarType>>::Color>,
-{
-    let gradient_desc = color::gradient::GradientDescriptor::new(colors);
-    let generator_type = matches
+) -> Result<color::gradient::GradientOptions, Error> {
+    let colors = parse_colors(matches.get_many::<String>("colors").unwrap())?;
+    let generator = matches
         .get_one::<color::gradient::GradientGeneratorType>("generator")
         .unwrap()
         .to_owned();
-    return gradient_desc.generate(frames_len * loop_count, generator_type);
+
+    return Ok(color::gradient::GradientOptions::new(colors, generator));
 }
 
-#[allow(dead_code, unused_variables)]
+#[allow(dead_code, unused_variables, clippy::excessive_precision)]
 pub fn get_gradient_2<C>(
     matches: &clap::ArgMatches,
     colors: vec::Vec<C>,
@@ -226,3 +310,23 @@ where
         C::from_color(color::ColorType::new(1., 0., 0.16015913371298365, 1.)),
     ];
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_colors, parse_rgb};
+
+    #[test]
+    fn test_parse_colors() {
+        assert_eq!(parse_rgb("ff8000").unwrap(), [255, 128, 0]);
+
+        let colors = parse_colors(["ff8000", "000000"]).unwrap();
+        assert_eq!(colors.len(), 2);
+        assert_eq!(colors[0].red, 1.0);
+        assert_eq!(colors[1].alpha, 1.0);
+
+        for invalid in ["aé123", "+f0000", "ff800", "gg0000"] {
+            assert!(parse_rgb(invalid).is_err(), "{}", invalid);
+            assert!(parse_colors([invalid]).is_err(), "{}", invalid);
+        }
+    }
+}
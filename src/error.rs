@@ -0,0 +1,104 @@
+/* The error returned by everything public in the crate
+ *
+ * Each module keeps describing its failures with its own error type, and this wraps them so callers
+ * can match on which part of the pipeline failed. Display passes straight through to the wrapped
+ * error, which is also the first source() so walking the chain still gets to the module's error.
+ */
+
+use std::error;
+use std::fmt;
+use std::io;
+
+use crate::{codec, color, commandline};
+
+#[derive(Debug)]
+pub enum Error {
+    Decode(codec::DecodeError),
+    Encode(codec::EncodeError),
+    Quantize(color::quantize::QuantizeError),
+    Commandline(commandline::CommandlineError),
+    Io(io::Error),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        return match self {
+            Self::Decode(e) => e.fmt(f),
+            Self::Encode(e) => e.fmt(f),
+            Self::Quantize(e) => e.fmt(f),
+            Self::Commandline(e) => e.fmt(f),
+            Self::Io(e) => e.fmt(f),
+        };
+    }
+}
+
+impl error::Error for Error {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        return match self {
+            Self::Decode(e) => Some(e),
+            Self::Encode(e) => Some(e),
+            Self::Quantize(e) => Some(e),
+            Self::Commandline(e) => Some(e),
+            Self::Io(e) => Some(e),
+        };
+    }
+}
+
+impl From<codec::DecodeError> for Error {
+    fn from(e: codec::DecodeError) -> Self {
+        return Self::Decode(e);
+    }
+}
+
+impl From<codec::EncodeError> for Error {
+    fn from(e: codec::EncodeError) -> Self {
+        return Self::Encode(e);
+    }
+}
+
+impl From<color::quantize::QuantizeError> for Error {
+    fn from(e: color::quantize::QuantizeError) -> Self {
+        return Self::Quantize(e);
+    }
+}
+
+impl From<commandline::CommandlineError> for Error {
+    fn from(e: commandline::CommandlineError) -> Self {
+        return Self::Commandline(e);
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        return Self::Io(e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::error::Error as _;
+
+    use super::Error;
+    use crate::codec;
+
+    #[test]
+    fn test_source_chain() {
+        let error = Error::from(codec::DecodeError::Read(
+            Some(Box::new(std::io::Error::other("truncated"))),
+            "Couldn't read the header".to_owned(),
+        ));
+
+        assert!(matches!(error, Error::Decode(codec::DecodeError::Read(..))));
+        assert_eq!(
+            error.to_string(),
+            "Error reading data: Couldn't read the header"
+        );
+        let source = error.source().unwrap();
+        assert!(source.is::<codec::DecodeError>());
+        assert_eq!(source.source().unwrap().to_string(), "truncated");
+
+        // so it can be sent back from worker threads
+        fn is_send_sync<T: Send + Sync>() {}
+        is_send_sync::<Error>();
+    }
+}
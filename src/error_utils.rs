@@ -4,7 +4,7 @@ macro_rules! define_error {
         #[allow(dead_code)]
         pub enum $x {
             $(
-                $y(Option<Box<dyn std::error::Error>>, String),
+                $y(Option<Box<dyn std::error::Error + Send + Sync>>, String),
             )*
         }
 
@@ -22,7 +22,9 @@ macro_rules! define_error {
             fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
                 return match self {
                     $(
-                        Self::$y(src, _) => src.as_ref().map(|e| e.as_ref()),
+                        Self::$y(src, _) => src
+                            .as_ref()
+                            .map(|e| e.as_ref() as &(dyn std::error::Error + 'static)),
                     )*
                 };
             }
@@ -2,4 +2,8 @@ pub mod buffer;
 pub mod codec;
 pub mod color;
 pub mod commandline;
+mod error;
 pub mod error_utils;
+pub mod pipeline;
+
+pub use error::Error;
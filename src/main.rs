@@ -1,137 +1,39 @@
-use std::cmp;
 use std::error;
-use std::fmt;
 use std::fs;
 
-use clap::{arg, command, value_parser, ArgMatches};
-use palette;
+use clap::{arg, command, value_parser};
 
-use rainbowgif::{buffer, codec, color, commandline};
-
-fn mix_impl<C>(matches: ArgMatches, mix_fn: fn(&C, &C) -> C) -> Result<(), Box<dyn error::Error>>
-where
-    C: color::Color + palette::Clamp + fmt::Debug,
-    palette::rgb::Rgb<color::EncodingType, color::ScalarType>:
-        palette::convert::FromColorUnclamped<<C as palette::WithAlpha<color::ScalarType>>::Color>,
-{
-    let src_image_path = matches.get_one::<String>("input_file").unwrap();
-    let src_data = buffer::Data::from_path(src_image_path)?;
-    let mut decoder: Box<dyn codec::Decodable<OutputColor = C>> = {
-        if matches.get_flag("static") {
-            Box::new(codec::image::ImageDecoder::new(src_data.buffer, None)?)
-        } else {
-            Box::new(codec::gif::GifDecoder::new(src_data.buffer)?)
-        }
-    };
-
-    let dest_image_path = matches.get_one::<String>("output_file").unwrap();
-    let mut dest_data = buffer::Data::new();
-    let encoder = codec::gif::GifEncoder::new(dest_data.buffer, decoder.get_dimensions())?;
-
-    let loop_count = cmp::max(
-        matches.get_one::<u64>("loop_count").unwrap().to_owned() as usize,
-        1usize,
-    );
-    // TODO: figure out either how to generate colors without knowing the frame count OR figure out
-    // how to get the frame count while streaming the decoding process (not decoding everything at
-    // once)
-
-    // automatically transform to the specified color space in the decoder
-    let frames = decoder.decode_all()?.unwrap();
-    let frames_len = frames.len();
-    let input_colors = commandline::get_colors::<C>(&matches)?;
-    let colors = commandline::get_gradient(&matches, input_colors, frames_len, loop_count);
-
-    // let rgba_colors: vec::Vec<color::ColorType> = colors.iter().map(|e| color::ColorType::from_color(e.clone())).collect();
-    // println!("{:#?}", rgba_colors[20]);
-
-    // TODO find a better way to do this, rather than cloning every loop
-    for l in 0usize..loop_count {
-        for (i, frame) in frames.iter().enumerate() {
-            let new_color = &colors[i + (frames_len * l)];
-
-            let mut cloned = frame.clone();
-            cloned
-                .palette
-                .colors
-                .iter_mut()
-                .for_each(|c| *c = mix_fn(c, new_color));
-
-            encoder.write(cloned)?;
-        }
-    }
-
-    dest_data.buffer = encoder.into_inner()?;
-    let _ = fs::write(dest_image_path, dest_data.buffer.get_ref())?;
-
-    return Ok(());
-}
-
-fn mix_none<C>(matches: ArgMatches) -> Result<(), Box<dyn error::Error>>
-where
-    C: color::Color + palette::Clamp + fmt::Debug,
-    palette::rgb::Rgb<color::EncodingType, color::ScalarType>:
-        palette::convert::FromColorUnclamped<<C as palette::WithAlpha<color::ScalarType>>::Color>,
-{
-    return mix_impl(matches, |a: &C, _: &C| {
-        return a.clone();
-    });
-}
-
-fn mix_custom<H, C>(matches: ArgMatches) -> Result<(), Box<dyn error::Error>>
-where
-    C: color::Color
-        + palette::Clamp
-        + color::Componentize<H, color::ScalarType, color::ScalarType, color::ScalarType>
-        + fmt::Debug,
-    palette::rgb::Rgb<color::EncodingType, color::ScalarType>:
-        palette::convert::FromColorUnclamped<<C as palette::WithAlpha<color::ScalarType>>::Color>,
-{
-    return mix_impl(matches, |a, b| {
-        return color::blend_colors::<H, color::ScalarType, color::ScalarType, color::ScalarType, C>(
-            a, b, true,
-        );
-    });
-}
-
-fn mix_lab(matches: ArgMatches) -> Result<(), Box<dyn error::Error>> {
-    return mix_impl(
-        matches,
-        |a: &palette::Laba<color::WhitePoint, color::ScalarType>,
-         b: &palette::Laba<color::WhitePoint, color::ScalarType>| {
-            return palette::Laba::from_components((a.l, b.a, b.b, a.alpha));
-        },
-    );
-}
-
-fn mix_linear<C>(matches: ArgMatches) -> Result<(), Box<dyn error::Error>>
-where
-    C: color::Color + palette::Clamp + fmt::Debug,
-    palette::rgb::Rgb<color::EncodingType, color::ScalarType>:
-        palette::convert::FromColorUnclamped<<C as palette::WithAlpha<color::ScalarType>>::Color>,
-{
-    // this isn't quite right, but again linear mixing might just not be ever
-    return mix_impl(matches, |a: &C, b: &C| {
-        let (_, a_alpha) = a.clone().split();
-        if a_alpha <= 0.5 {
-            return a.clone();
-        }
-
-        return a.mix(b, 0.2);
-    });
-}
+use rainbowgif::{buffer, codec, color, commandline, pipeline};
 
 fn main() -> Result<(), Box<dyn error::Error>> {
     let matches = command!()
         .arg(arg!(input_file: <INPUT_FILE> "The path to the input file"))
         .arg(arg!(output_file: <OUTPUT_FILE> "The path to the output file"))
-        .arg(arg!(static: --static "Whether the input is static or not"))
+        .arg(arg!(static: --static "Treat an animated input as a static image of its first frame"))
         .arg(
-            arg!(loop_count: --loop_count [LOOP_COUNT] "Number of times to loop for a GIF and for a static input, the resulting number of frames")
+            arg!(loop_count: --loop_count [LOOP_COUNT] "Number of times to loop for an animated input and for a static input, the resulting number of frames")
                 .value_parser(clap::value_parser!(u64)
                 .range(1..))
                 .default_value("1")
         )
+        .arg(
+            arg!(threads: -t --threads [THREADS] "How many threads recolor the frames, defaults to the number of cores")
+                .value_parser(value_parser!(u64).range(1..))
+        )
+        .arg(
+            arg!(delay: --delay [DELAY] "Show every frame for this many hundredths of a second")
+                .value_parser(value_parser!(u16))
+                .conflicts_with_all(["playback_speed", "duration"])
+        )
+        .arg(
+            arg!(playback_speed: --playback_speed [PLAYBACK_SPEED] "Play an animated input this many times faster, so 0.5 is half as fast")
+                .value_parser(value_parser!(f64))
+                .conflicts_with("duration")
+        )
+        .arg(
+            arg!(duration: --duration [DURATION] "Make the whole output, every loop included, last this many hundredths of a second")
+                .value_parser(value_parser!(u32).range(1..))
+        )
         .arg(
             arg!(colors: -c --colors [COLORS] "The colors to use in the gradient")
                 .value_delimiter(',')
@@ -152,93 +54,209 @@ fn main() -> Result<(), Box<dyn error::Error>> {
             .value_parser(value_parser!(color::MixingMode))
             .default_value("custom")
             )
+        .arg(
+            arg!(output_format: -f --output_format [OUTPUT_FORMAT] "The format to write, guessed from the output file extension if not given")
+            .value_parser(value_parser!(codec::OutputFormat))
+            )
+        .arg(
+            arg!(repeat: --repeat [REPEAT] "How many more times the animation plays after the first time, or infinite. Taken from the input if not given")
+            .value_parser(commandline::parse_repeat)
+            )
+        .arg(arg!(comment: --comment [COMMENT] "A comment to add to the output, after the ones carried over from the input"))
+        .arg(arg!(global_palette: --global_palette "Share one palette between the GIF frames whose colors fit together, for smaller files"))
+        .arg(arg!(optimize: -O --optimize "Only write the pixels of a GIF frame that changed from the previous one, for smaller files"))
+        .arg(arg!(lenient: --lenient "Keep the frames read before a broken input breaks off, rather than failing"))
+        .arg(arg!(verify_palette: --verify_palette "Fail if a GIF's palette colors can't be written back out unchanged"))
+        .arg(
+            arg!(alpha_threshold: --alpha_threshold [ALPHA_THRESHOLD] "The alpha (0-255) a pixel needs to stay visible in outputs without partial transparency")
+            .value_parser(value_parser!(u8))
+            .default_value("255")
+            )
+        .arg(
+            arg!(matte: --matte [MATTE] "A color to composite semi-transparent pixels onto in outputs without partial transparency")
+            .value_parser(commandline::parse_rgb)
+            )
+        .arg(arg!(preserve_alpha: --preserve_alpha "Keep partial transparency in APNG and WebP output"))
+        .arg(arg!(downscale: --downscale "Scale inputs larger than 65535 pixels wide or high down to fit, instead of failing"))
+        .arg(
+            arg!(crop: --crop [CROP] "Only keep this part of the input, as WIDTHxHEIGHT+X+Y")
+            .value_parser(commandline::parse_crop)
+            )
+        .arg(
+            arg!(resize: --resize [RESIZE] "Resize the output to WIDTHxHEIGHT, after cropping")
+            .value_parser(commandline::parse_size)
+            )
+        .arg(
+            arg!(resize_filter: --resize_filter [RESIZE_FILTER] "How pixels are sampled when resizing")
+            .value_parser(value_parser!(codec::transform::ResizeFilter))
+            .default_value("lanczos")
+            )
+        .arg(arg!(letterbox: --letterbox "Keep the aspect ratio when resizing, filling the rest with transparency"))
+        .arg(
+            arg!(pad: --pad [PAD] "Center the output on a transparent canvas of at least WIDTHxHEIGHT")
+            .value_parser(commandline::parse_size)
+            )
+        .arg(
+            arg!(quantizer: -q --quantizer [QUANTIZER] "How to reduce the colors of inputs that aren't already indexed, like static images")
+            .value_parser(value_parser!(color::quantize::QuantizerType))
+            .default_value("imagequant")
+            )
+        .arg(
+            arg!(dithering: -d --dithering [DITHERING] "How to dither inputs that get quantized")
+            .value_parser(value_parser!(color::quantize::dither::DitherType))
+            .default_value("floyd_steinberg")
+            )
+        .arg(
+            arg!(dithering_level: --dithering_level [DITHERING_LEVEL] "How strong the dithering is, from 0 to 1")
+            .value_parser(value_parser!(f32))
+            .default_value("1.0")
+            )
+        .arg(
+            arg!(max_colors: --max_colors [MAX_COLORS] "The most colors a quantized frame can have")
+            .value_parser(value_parser!(u16).range(2..=256))
+            .default_value("256")
+            )
+        .arg(
+            arg!(min_quality: --min_quality [MIN_QUALITY] "The quality (0-100) below which imagequant gives up instead of quantizing")
+            .value_parser(value_parser!(u8).range(0..=100))
+            .default_value("0")
+            )
+        .arg(
+            arg!(max_quality: --max_quality [MAX_QUALITY] "The quality (0-100) at which imagequant stops adding colors")
+            .value_parser(value_parser!(u8).range(0..=100))
+            .default_value("100")
+            )
+        .arg(
+            arg!(speed: --speed [SPEED] "The speed of imagequant, from 1 (slowest and best) to 10 (fastest)")
+            .value_parser(value_parser!(i32).range(1..=10))
+            .default_value("5")
+            )
+        .arg(
+            arg!(posterization: --posterization [POSTERIZATION] "The number of low bits dropped from quantized colors, which compresses better")
+            .value_parser(value_parser!(u8).range(0..=4))
+            .default_value("0")
+            )
+        .arg(arg!(report: --report "Print the quality and error of inputs that got quantized"))
         .get_matches();
 
-    let color_space = matches.get_one::<color::ColorSpace>("color_space").unwrap();
-
-    match matches.get_one::<color::MixingMode>("mixing_mode").unwrap() {
-        color::MixingMode::None => match color_space {
-            color::ColorSpace::HSL => {
-                mix_none::<palette::Hsla<color::EncodingType, color::ScalarType>>(matches)
-            }
-
-            color::ColorSpace::HSV => {
-                mix_none::<palette::Hsva<color::EncodingType, color::ScalarType>>(matches)
-            }
-
-            color::ColorSpace::LAB => {
-                mix_none::<palette::Laba<color::WhitePoint, color::ScalarType>>(matches)
-            }
-
-            color::ColorSpace::LCH => {
-                mix_none::<palette::Lcha<color::WhitePoint, color::ScalarType>>(matches)
-            }
-
-            _ => Err(Box::new(commandline::CommandlineError::IncompatibleValue(
-                None,
-                "Only HSL, HSV, LAB, and LCH are supported for custom mixing mode".to_owned(),
-            ))),
-        },
-
-        color::MixingMode::Custom => match color_space {
-            color::ColorSpace::HSL => mix_custom::<
-                palette::RgbHue<color::ScalarType>,
-                palette::Hsla<color::EncodingType, color::ScalarType>,
-            >(matches),
-
-            color::ColorSpace::HSV => mix_custom::<
-                palette::RgbHue<color::ScalarType>,
-                palette::Hsva<color::EncodingType, color::ScalarType>,
-            >(matches),
-
-            color::ColorSpace::LCH => mix_custom::<
-                palette::LabHue<color::ScalarType>,
-                palette::Lcha<color::WhitePoint, color::ScalarType>,
-            >(matches),
-
-            _ => Err(Box::new(commandline::CommandlineError::IncompatibleValue(
-                None,
-                "Only HSL, HSV, and LCH are supported for custom mixing mode".to_owned(),
-            ))),
-        },
-
-        color::MixingMode::Lab => match color_space {
-            color::ColorSpace::LAB => mix_lab(matches),
+    let src_image_path = matches.get_one::<String>("input_file").unwrap();
+    let src_data = buffer::Data::from_path(src_image_path)?;
 
-            _ => Err(Box::new(commandline::CommandlineError::IncompatibleValue(
-                None,
-                "Only LAB is supported for lab mixing mode".to_owned(),
-            ))),
-        },
+    let rainbowify = pipeline::Rainbowify::from_bytes(src_data.buffer.into_inner())
+        .is_static(matches.get_flag("static"))
+        .global_palette(matches.get_flag("global_palette"))
+        .optimize(matches.get_flag("optimize"))
+        .lenient(matches.get_flag("lenient"))
+        .verify_palette(matches.get_flag("verify_palette"))
+        .alpha_threshold(matches.get_one::<u8>("alpha_threshold").unwrap().to_owned())
+        .matte(matches.get_one::<[u8; 3]>("matte").copied())
+        .preserve_alpha(matches.get_flag("preserve_alpha"))
+        .downscale(matches.get_flag("downscale"))
+        .crop(matches.get_one::<((u16, u16), (u16, u16))>("crop").copied())
+        .resize(matches.get_one::<(u16, u16)>("resize").copied())
+        .resize_filter(
+            matches
+                .get_one::<codec::transform::ResizeFilter>("resize_filter")
+                .unwrap()
+                .to_owned(),
+        )
+        .letterbox(matches.get_flag("letterbox"))
+        .pad(matches.get_one::<(u16, u16)>("pad").copied())
+        .gradient(commandline::get_gradient_options(&matches)?)
+        .color_space(
+            matches
+                .get_one::<color::ColorSpace>("color_space")
+                .unwrap()
+                .to_owned(),
+        )
+        .mixing_mode(
+            matches
+                .get_one::<color::MixingMode>("mixing_mode")
+                .unwrap()
+                .to_owned(),
+        )
+        .loop_count(matches.get_one::<u64>("loop_count").unwrap().to_owned() as usize)
+        .quantizer(
+            matches
+                .get_one::<color::quantize::QuantizerType>("quantizer")
+                .unwrap()
+                .to_owned(),
+        )
+        .dithering(
+            matches
+                .get_one::<color::quantize::dither::DitherType>("dithering")
+                .unwrap()
+                .to_owned(),
+        )
+        .dithering_level(
+            matches
+                .get_one::<f32>("dithering_level")
+                .unwrap()
+                .to_owned(),
+        )
+        .max_colors(matches.get_one::<u16>("max_colors").unwrap().to_owned() as usize)
+        .quality(
+            matches.get_one::<u8>("min_quality").unwrap().to_owned(),
+            matches.get_one::<u8>("max_quality").unwrap().to_owned(),
+        )
+        .speed(matches.get_one::<i32>("speed").unwrap().to_owned())
+        .posterization(matches.get_one::<u8>("posterization").unwrap().to_owned());
+
+    let timing = if let Some(delay) = matches.get_one::<u16>("delay") {
+        pipeline::Timing::Fixed(*delay)
+    } else if let Some(speed) = matches.get_one::<f64>("playback_speed") {
+        pipeline::Timing::Speed(*speed)
+    } else if let Some(duration) = matches.get_one::<u32>("duration") {
+        pipeline::Timing::Duration(*duration)
+    } else {
+        pipeline::Timing::Keep
+    };
+    let rainbowify = rainbowify.timing(timing);
 
-        color::MixingMode::Linear => match color_space {
-            color::ColorSpace::HSL => {
-                mix_linear::<palette::Hsla<color::EncodingType, color::ScalarType>>(matches)
-            }
+    let rainbowify = match matches.get_one::<u64>("threads") {
+        Some(threads) => rainbowify.threads(*threads as usize),
+        None => rainbowify,
+    };
 
-            color::ColorSpace::HSV => {
-                mix_linear::<palette::Hsva<color::EncodingType, color::ScalarType>>(matches)
-            }
+    let rainbowify = match matches.get_one::<gif::Repeat>("repeat") {
+        Some(repeat) => rainbowify.repeat(*repeat),
+        None => rainbowify,
+    };
+    let rainbowify = match matches.get_one::<String>("comment") {
+        Some(comment) => rainbowify.comment(comment.to_owned()),
+        None => rainbowify,
+    };
 
-            color::ColorSpace::LAB => {
-                mix_linear::<palette::Laba<color::WhitePoint, color::ScalarType>>(matches)
-            }
+    let dest_image_path = matches.get_one::<String>("output_file").unwrap();
+    let output_format = matches
+        .get_one::<codec::OutputFormat>("output_format")
+        .copied()
+        .or_else(|| codec::OutputFormat::from_path(dest_image_path))
+        .unwrap_or(codec::OutputFormat::Gif);
+
+    let (output, report) = rainbowify
+        .output_format(output_format)
+        .encode_with_report()?;
+    fs::write(dest_image_path, output)?;
+
+    if let Some((frames, error)) = &report.recovered {
+        eprintln!(
+            "Warning: the input broke off after {} frames, so only those were used: {}",
+            frames, error
+        );
+    }
 
-            color::ColorSpace::LCH => {
-                mix_linear::<palette::Lcha<color::WhitePoint, color::ScalarType>>(matches)
+    if matches.get_flag("report") {
+        match report.quantize {
+            Some(color::quantize::QuantizeReport { quality, error }) => {
+                if let Some(quality) = quality {
+                    println!("quality: {}", quality);
+                }
+                println!("error: {:.3}", error);
             }
-
-            _ => Err(Box::new(commandline::CommandlineError::IncompatibleValue(
-                None,
-                "Only HSL, HSV, LAB, and LCH are supported for custom mixing mode".to_owned(),
-            ))),
-        },
-
-        color::MixingMode::BlendOverlay => {
-            Err(Box::new(commandline::CommandlineError::NotImplemented(
-                None,
-                "Blend overlay mixing is not implemented".to_owned(),
-            )))
+            None => println!("The input was already indexed, so nothing was quantized"),
         }
     }
+
+    return Ok(());
 }
@@ -0,0 +1,1193 @@
+use std::cmp;
+use std::collections;
+use std::io;
+use std::iter;
+use std::panic;
+use std::sync;
+use std::thread;
+use std::vec;
+
+use crate::{codec, color, commandline, Error};
+
+// browsers show frames with a shorter delay for 10 centiseconds instead, so this is as fast as it gets
+pub const MIN_DELAY: u16 = 2;
+// still images have no delay of their own, so they're shown for as long as browsers would show one
+pub const STILL_DELAY: u16 = 10;
+
+// How long the output frames are shown for, in centiseconds. The delays are never shorter than
+// MIN_DELAY.
+#[derive(Clone, Copy, Debug)]
+pub enum Timing {
+    // the delays of the input, which are STILL_DELAY for still images
+    Keep,
+    Fixed(u16),
+    // the delays of the input divided by this, so 2 plays twice as fast
+    Speed(f64),
+    // the whole output, every loop included, takes this long with the frames evenly split
+    Duration(u32),
+}
+
+impl Timing {
+    // the delay of the index-th of count output frames, where the input frame had the given delay
+    pub fn delay(&self, delay: u16, index: usize, count: usize) -> u16 {
+        let new_delay = match *self {
+            Timing::Keep => delay as u64,
+            Timing::Fixed(fixed) => fixed as u64,
+            Timing::Speed(speed) => (delay as f64 / speed).round() as u64,
+            // the remainder is spread out rather than all going to the last frame
+            Timing::Duration(total) => {
+                let (total, index, count) = (total as u64, index as u64, cmp::max(count, 1) as u64);
+                (((index + 1) * total) / count) - ((index * total) / count)
+            }
+        };
+
+        return new_delay.clamp(MIN_DELAY as u64, u16::MAX as u64) as u16;
+    }
+
+    fn validate(&self) -> Result<(), Error> {
+        let message = match *self {
+            Timing::Speed(speed) if !(speed.is_finite() && speed > 0.) => {
+                format!("The speed has to be above 0, but got {}", speed)
+            }
+            Timing::Duration(0) => "The duration has to be above 0".to_owned(),
+            _ => return Ok(()),
+        };
+
+        return Err(Error::Commandline(
+            commandline::CommandlineError::InvalidValue(None, message),
+        ));
+    }
+}
+
+// What happened to the input on its way through
+#[derive(Debug, Default)]
+pub struct Report {
+    // how well the input survived quantizing, None for inputs that were already indexed
+    pub quantize: Option<color::quantize::QuantizeReport>,
+
+    // in lenient mode, the error a broken input stopped at and how many frames were read before it
+    pub recovered: Option<(usize, Error)>,
+}
+
+// Decodes the input, generates the gradient, mixes every frame with its color and encodes the
+// result. Defaults match the ones the CLI uses.
+pub struct Rainbowify {
+    input: vec::Vec<u8>,
+    is_static: bool,
+    gradient: color::gradient::GradientOptions,
+    color_space: color::ColorSpace,
+    mixing_mode: color::MixingMode,
+    loop_count: usize,
+    threads: usize,
+    timing: Timing,
+    output_format: codec::OutputFormat,
+    global_palette: bool,
+    optimize: bool,
+    lenient: bool,
+    verify_palette: bool,
+    repeat: Option<::gif::Repeat>,
+    comment: Option<String>,
+    quantizer: color::quantize::Quantizer,
+    transparency: color::transparency::Transparency,
+    downscale: bool,
+    transform: codec::transform::Transform,
+}
+
+impl Rainbowify {
+    pub fn from_bytes(input: vec::Vec<u8>) -> Self {
+        return Rainbowify {
+            input,
+            is_static: false,
+            gradient: color::gradient::GradientOptions::default(),
+            color_space: color::ColorSpace::LCH,
+            mixing_mode: color::MixingMode::Custom,
+            loop_count: 1,
+            threads: thread::available_parallelism().map_or(1, |n| n.get()),
+            timing: Timing::Keep,
+            output_format: codec::OutputFormat::Gif,
+            global_palette: false,
+            optimize: false,
+            lenient: false,
+            verify_palette: false,
+            repeat: None,
+            comment: None,
+            quantizer: color::quantize::Quantizer::new(
+                256,
+                color::quantize::QuantizerType::IMAGEQUANT,
+            ),
+            transparency: color::transparency::Transparency::default(),
+            downscale: false,
+            transform: codec::transform::Transform::default(),
+        };
+    }
+
+    pub fn from_reader<R: io::Read>(mut read: R) -> Result<Self, Error> {
+        let mut input = vec::Vec::new();
+        read.read_to_end(&mut input)?;
+
+        return Ok(Self::from_bytes(input));
+    }
+
+    // the input format is detected from its contents, this only forces an animated input to be
+    // treated as a static image made of its first frame
+    pub fn is_static(mut self, is_static: bool) -> Self {
+        self.is_static = is_static;
+        return self;
+    }
+
+    pub fn gradient(mut self, gradient: color::gradient::GradientOptions) -> Self {
+        self.gradient = gradient;
+        return self;
+    }
+
+    pub fn colors(mut self, colors: vec::Vec<color::ColorType>) -> Self {
+        self.gradient.colors = colors;
+        return self;
+    }
+
+    pub fn generator(mut self, generator: color::gradient::GradientGeneratorType) -> Self {
+        self.gradient.generator = generator;
+        return self;
+    }
+
+    pub fn color_space(mut self, color_space: color::ColorSpace) -> Self {
+        self.color_space = color_space;
+        return self;
+    }
+
+    pub fn mixing_mode(mut self, mixing_mode: color::MixingMode) -> Self {
+        self.mixing_mode = mixing_mode;
+        return self;
+    }
+
+    // for an animated input the number of times to loop over it, for a static input the number of frames
+    pub fn loop_count(mut self, loop_count: usize) -> Self {
+        self.loop_count = cmp::max(loop_count, 1);
+        return self;
+    }
+
+    // how many threads recolor the frames, where 1 does everything on the calling thread
+    pub fn threads(mut self, threads: usize) -> Self {
+        self.threads = cmp::max(threads, 1);
+        return self;
+    }
+
+    pub fn timing(mut self, timing: Timing) -> Self {
+        self.timing = timing;
+        return self;
+    }
+
+    pub fn output_format(mut self, output_format: codec::OutputFormat) -> Self {
+        self.output_format = output_format;
+        return self;
+    }
+
+    // how many more times the animation plays after the first time, instead of the input's own
+    // repeat count
+    pub fn repeat(mut self, repeat: ::gif::Repeat) -> Self {
+        self.repeat = Some(repeat);
+        return self;
+    }
+
+    // added after the input's own comments, for the formats that have them
+    pub fn comment(mut self, comment: String) -> Self {
+        self.comment = Some(comment);
+        return self;
+    }
+
+    // only for GIF output, frames whose colors fit together share one global palette instead of
+    // each carrying its own, at the cost of holding every frame in memory until the end
+    pub fn global_palette(mut self, global_palette: bool) -> Self {
+        self.global_palette = global_palette;
+        return self;
+    }
+
+    // only for GIF output, only writes the pixels that changed from the previous frame
+    pub fn optimize(mut self, optimize: bool) -> Self {
+        self.optimize = optimize;
+        return self;
+    }
+
+    // an animated input that breaks off partway is cut short to the frames before the break, which
+    // ends up in the report, rather than failing
+    pub fn lenient(mut self, lenient: bool) -> Self {
+        self.lenient = lenient;
+        return self;
+    }
+
+    // fails rather than drifting when a GIF's palette doesn't survive the trip through the color
+    // space, so with the none mixing mode the output palettes are the input's byte for byte
+    pub fn verify_palette(mut self, verify_palette: bool) -> Self {
+        self.verify_palette = verify_palette;
+        return self;
+    }
+
+    // how inputs that aren't already indexed, like static images, get reduced to a palette
+    pub fn quantizer(mut self, quantizer_type: color::quantize::QuantizerType) -> Self {
+        self.quantizer.quantizer_type = quantizer_type;
+        return self;
+    }
+
+    pub fn dithering(mut self, dither_type: color::quantize::dither::DitherType) -> Self {
+        self.quantizer.dither_type = dither_type;
+        return self;
+    }
+
+    // between 0 and 1, scales how much of the error gets spread or how strong the pattern is
+    pub fn dithering_level(mut self, dithering_level: f32) -> Self {
+        self.quantizer.dithering_level = dithering_level;
+        return self;
+    }
+
+    // the color budget of every quantized frame, between 2 and 256
+    pub fn max_colors(mut self, max_colors: usize) -> Self {
+        self.quantizer.max_color_count = max_colors;
+        return self;
+    }
+
+    // quantizing fails when the minimum can't be reached, and stops adding colors at the maximum
+    pub fn quality(mut self, min_quality: u8, max_quality: u8) -> Self {
+        self.quantizer.min_quality = min_quality;
+        self.quantizer.max_quality = max_quality;
+        return self;
+    }
+
+    // 1 is the slowest and best, 10 the fastest
+    pub fn speed(mut self, speed: i32) -> Self {
+        self.quantizer.speed = speed;
+        return self;
+    }
+
+    // the number of low bits dropped from palette colors, up to 4
+    pub fn posterization(mut self, posterization: u8) -> Self {
+        self.quantizer.posterization = posterization;
+        return self;
+    }
+
+    // the alpha a pixel needs to stay visible when partial alpha can't be kept
+    pub fn alpha_threshold(mut self, threshold: u8) -> Self {
+        self.transparency.threshold = threshold;
+        return self;
+    }
+
+    // semi-transparent pixels are composited onto this color when partial alpha can't be kept
+    pub fn matte(mut self, matte: Option<[u8; 3]>) -> Self {
+        self.transparency.matte = matte;
+        return self;
+    }
+
+    // keeps partial alpha for the output formats that can store it, which GIF can't
+    pub fn preserve_alpha(mut self, preserve_alpha: bool) -> Self {
+        self.transparency.preserve_alpha = preserve_alpha;
+        return self;
+    }
+
+    // scales inputs larger than a GIF frame can be down to fit, instead of failing on them
+    pub fn downscale(mut self, downscale: bool) -> Self {
+        self.downscale = downscale;
+        return self;
+    }
+
+    // the origin and dimensions of the part of the input that's kept
+    pub fn crop(mut self, crop: Option<((u16, u16), (u16, u16))>) -> Self {
+        self.transform.crop = crop;
+        return self;
+    }
+
+    // the exact output size, which letterboxing fits the input into without stretching it
+    pub fn resize(mut self, resize: Option<(u16, u16)>) -> Self {
+        self.transform.resize = resize;
+        return self;
+    }
+
+    pub fn resize_filter(mut self, filter: codec::transform::ResizeFilter) -> Self {
+        self.transform.filter = filter;
+        return self;
+    }
+
+    pub fn letterbox(mut self, letterbox: bool) -> Self {
+        self.transform.letterbox = letterbox;
+        return self;
+    }
+
+    // the smallest output size, with the input centered on a transparent canvas
+    pub fn pad(mut self, pad: Option<(u16, u16)>) -> Self {
+        self.transform.pad = pad;
+        return self;
+    }
+
+    pub fn encode(&self) -> Result<vec::Vec<u8>, Error> {
+        return self.encode_with_report().map(|(output, _)| output);
+    }
+
+    pub fn encode_with_report(&self) -> Result<(vec::Vec<u8>, Report), Error> {
+        let mut output = vec::Vec::new();
+        let report = self.write_with_report(&mut output)?;
+
+        return Ok((output, report));
+    }
+
+    pub fn write<W: io::Write>(&self, w: W) -> Result<(), Error> {
+        return self.write_with_report(w).map(|_| ());
+    }
+
+    pub fn write_with_report<W: io::Write>(&self, w: W) -> Result<Report, Error> {
+        if self.gradient.colors.is_empty() {
+            return Err(Error::Commandline(
+                commandline::CommandlineError::InvalidValue(
+                    None,
+                    "At least one color is needed for the gradient".to_owned(),
+                ),
+            ));
+        }
+
+        self.timing.validate()?;
+
+        let color_space = self.color_space;
+
+        return match self.mixing_mode {
+            color::MixingMode::None => match color_space {
+                color::ColorSpace::HSL => {
+                    self.mix_none::<palette::Hsla<color::EncodingType, color::ScalarType>, W>(w)
+                }
+
+                color::ColorSpace::HSV => {
+                    self.mix_none::<palette::Hsva<color::EncodingType, color::ScalarType>, W>(w)
+                }
+
+                color::ColorSpace::LAB => {
+                    self.mix_none::<palette::Laba<color::WhitePoint, color::ScalarType>, W>(w)
+                }
+
+                color::ColorSpace::LCH => {
+                    self.mix_none::<palette::Lcha<color::WhitePoint, color::ScalarType>, W>(w)
+                }
+
+                color::ColorSpace::OKLAB => self.mix_none::<palette::Oklaba<color::ScalarType>, W>(w),
+
+                color::ColorSpace::OKLCH => self.mix_none::<palette::Oklcha<color::ScalarType>, W>(w),
+
+                _ => Err(Error::Commandline(commandline::CommandlineError::IncompatibleValue(
+                    None,
+                    "Only HSL, HSV, LAB, LCH, OKLAB, and OKLCH are supported for none mixing mode"
+                        .to_owned(),
+                ))),
+            },
+
+            color::MixingMode::Custom => match color_space {
+                color::ColorSpace::HSL => self.mix_custom::<
+                    palette::RgbHue<color::ScalarType>,
+                    palette::Hsla<color::EncodingType, color::ScalarType>,
+                    W,
+                >(w),
+
+                color::ColorSpace::HSV => self.mix_custom::<
+                    palette::RgbHue<color::ScalarType>,
+                    palette::Hsva<color::EncodingType, color::ScalarType>,
+                    W,
+                >(w),
+
+                color::ColorSpace::LCH => self.mix_custom::<
+                    palette::LabHue<color::ScalarType>,
+                    palette::Lcha<color::WhitePoint, color::ScalarType>,
+                    W,
+                >(w),
+
+                color::ColorSpace::OKLAB => self.mix_custom::<
+                    palette::OklabHue<color::ScalarType>,
+                    palette::Oklaba<color::ScalarType>,
+                    W,
+                >(w),
+
+                color::ColorSpace::OKLCH => self.mix_custom::<
+                    palette::OklabHue<color::ScalarType>,
+                    palette::Oklcha<color::ScalarType>,
+                    W,
+                >(w),
+
+                _ => Err(Error::Commandline(commandline::CommandlineError::IncompatibleValue(
+                    None,
+                    "Only HSL, HSV, LCH, OKLAB, and OKLCH are supported for custom mixing mode"
+                        .to_owned(),
+                ))),
+            },
+
+            color::MixingMode::Lab => match color_space {
+                color::ColorSpace::LAB => self.mix_lab(w),
+
+                color::ColorSpace::OKLAB => self.mix_oklab(w),
+
+                _ => Err(Error::Commandline(commandline::CommandlineError::IncompatibleValue(
+                    None,
+                    "Only LAB and OKLAB are supported for lab mixing mode".to_owned(),
+                ))),
+            },
+
+            color::MixingMode::Linear => match color_space {
+                color::ColorSpace::HSL => {
+                    self.mix_linear::<palette::Hsla<color::EncodingType, color::ScalarType>, W>(w)
+                }
+
+                color::ColorSpace::HSV => {
+                    self.mix_linear::<palette::Hsva<color::EncodingType, color::ScalarType>, W>(w)
+                }
+
+                color::ColorSpace::LAB => {
+                    self.mix_linear::<palette::Laba<color::WhitePoint, color::ScalarType>, W>(w)
+                }
+
+                color::ColorSpace::LCH => {
+                    self.mix_linear::<palette::Lcha<color::WhitePoint, color::ScalarType>, W>(w)
+                }
+
+                color::ColorSpace::OKLAB => self.mix_linear::<palette::Oklaba<color::ScalarType>, W>(w),
+
+                color::ColorSpace::OKLCH => self.mix_linear::<palette::Oklcha<color::ScalarType>, W>(w),
+
+                _ => Err(Error::Commandline(commandline::CommandlineError::IncompatibleValue(
+                    None,
+                    "Only HSL, HSV, LAB, LCH, OKLAB, and OKLCH are supported for linear mixing mode"
+                        .to_owned(),
+                ))),
+            },
+
+            mixing_mode => {
+                let blend_mode = mixing_mode
+                    .blend_mode()
+                    .expect("the remaining mixing modes are all blend modes");
+
+                match color_space {
+                    color::ColorSpace::HSL => self
+                        .mix_blend::<palette::Hsla<color::EncodingType, color::ScalarType>, W>(
+                            w, blend_mode,
+                        ),
+
+                    color::ColorSpace::HSV => self
+                        .mix_blend::<palette::Hsva<color::EncodingType, color::ScalarType>, W>(
+                            w, blend_mode,
+                        ),
+
+                    color::ColorSpace::LAB => self
+                        .mix_blend::<palette::Laba<color::WhitePoint, color::ScalarType>, W>(
+                            w, blend_mode,
+                        ),
+
+                    color::ColorSpace::LCH => self
+                        .mix_blend::<palette::Lcha<color::WhitePoint, color::ScalarType>, W>(
+                            w, blend_mode,
+                        ),
+
+                    color::ColorSpace::OKLAB => {
+                        self.mix_blend::<palette::Oklaba<color::ScalarType>, W>(w, blend_mode)
+                    }
+
+                    color::ColorSpace::OKLCH => {
+                        self.mix_blend::<palette::Oklcha<color::ScalarType>, W>(w, blend_mode)
+                    }
+
+                    _ => Err(Error::Commandline(commandline::CommandlineError::IncompatibleValue(
+                        None,
+                        "Only HSL, HSV, LAB, LCH, OKLAB, and OKLCH are supported for blend mixing modes"
+                            .to_owned(),
+                    ))),
+                }
+            }
+        };
+    }
+
+    fn mix_impl<C, F, W>(&self, w: W, mix_fn: F) -> Result<Report, Error>
+    where
+        C: color::Color,
+        F: Fn(&C, &C) -> C + Sync,
+        W: io::Write,
+        palette::rgb::Rgb<color::EncodingType, color::ScalarType>:
+            palette::convert::FromColorUnclamped<
+                <C as palette::WithAlpha<color::ScalarType>>::Color,
+            >,
+    {
+        let src_bytes = &self.input[..];
+        let input_format = codec::InputFormat::sniff(src_bytes)?;
+
+        let transparency = color::transparency::Transparency {
+            preserve_alpha: self.transparency.preserve_alpha
+                && !matches!(self.output_format, codec::OutputFormat::Gif),
+            ..self.transparency
+        };
+        let options = codec::DecoderOptions {
+            quantizer: self.quantizer.clone(),
+            transparency,
+            downscale: self.downscale,
+        };
+
+        // automatically transform to the specified color space in the decoder, where resizing and
+        // the like happen on the decoded frames so everything after sees the output dimensions
+        let open = || -> Result<Box<dyn codec::Decodable<OutputColor = C> + '_>, Error> {
+            let mut decoder = input_format.open::<C>(src_bytes, &options)?;
+            decoder.set_verify_palette(self.verify_palette);
+            if self.transform.is_identity() {
+                return Ok(decoder);
+            }
+
+            let decoder = codec::transform::TransformDecoder::new(decoder, self.transform)?
+                .with_options(options.clone());
+            return Ok(Box::new(decoder));
+        };
+
+        // the gradient needs the frame count up front, so for animated inputs only the headers are
+        // walked first and the frames are then streamed through one at a time
+        let mut recovered = None;
+        let frames_len = if self.is_static {
+            1
+        } else if self.lenient {
+            // the headers can look fine for frames that don't decode, so everything gets decoded
+            // once to find the frames that do
+            let mut decoder = open()?;
+            let mut count = 0usize;
+            loop {
+                match decoder.decode_pending() {
+                    Ok(Some(_)) => count += 1,
+                    Ok(None) => break,
+                    Err(e) if count > 0 => {
+                        recovered = Some((count, e));
+                        break;
+                    }
+                    Err(e) => return Err(e),
+                }
+            }
+
+            count
+        } else {
+            input_format.count_frames(src_bytes)?
+        };
+        let is_single_frame = frames_len == 1;
+        let colors = self.gradient.generate::<C>(frames_len * self.loop_count);
+
+        let recolor = |frame: &mut codec::Frame<C>, new_color: &C| {
+            frame
+                .palette
+                .colors
+                .iter_mut()
+                .for_each(|c| *c = mix_fn(c, new_color));
+        };
+
+        let mut decoder = open()?;
+        let dimensions = decoder.get_dimensions();
+        // the reports of the frames the decoder quantized itself, and of the ones the workers did
+        let (mut report, mut finished_report) = (None, None);
+
+        let mut metadata = input_format
+            .read_metadata(src_bytes)
+            .unwrap_or_else(|| decoder.metadata());
+        // a static input only gets animated by the gradient, so it loops like the default
+        if is_single_frame {
+            metadata.repeat = codec::Metadata::default().repeat;
+        }
+        if let Some(repeat) = self.repeat {
+            metadata.repeat = repeat;
+        }
+        if let Some(comment) = &self.comment {
+            metadata.comments.push(comment.clone());
+        }
+
+        let mut write_frames =
+            |encoder: &dyn codec::Encodable<InputColor = C>| -> Result<(), Error> {
+                // a static input is a single frame (or only the first one when forced), so it's
+                // kept around and recolored for every loop
+                let still = if is_single_frame {
+                    let mut frame = decoder.decode()?.ok_or_else(|| {
+                        return codec::DecodeError::InvalidData(
+                            None,
+                            "Input had no frames".to_owned(),
+                        );
+                    })?;
+                    if frame.delay == 0 {
+                        frame.delay = STILL_DELAY;
+                    }
+                    Some(frame)
+                } else {
+                    None
+                };
+
+                // the frames with the index of their color, where every loop of an animated input
+                // decodes it again so only the frames in flight are ever held in memory
+                let (mut l, mut i) = (0usize, 0usize);
+                let frames = iter::from_fn(|| {
+                    if let Some(frame) = &still {
+                        // the pixels are shared between the copies, so this only copies the palette
+                        i += 1;
+                        return (i <= colors.len())
+                            .then(|| Ok((codec::PendingFrame::Indexed(frame.clone()), i - 1)));
+                    }
+
+                    while l < self.loop_count {
+                        // a broken input ends early, before the decoder gets to the error
+                        let decoded = if recovered.is_some() && i == frames_len {
+                            Ok(None)
+                        } else {
+                            decoder.decode_pending()
+                        };
+
+                        let frame = match decoded {
+                            Ok(Some(frame)) => frame,
+                            // the colors were made for the counted frames, so they'd go out of sync
+                            Ok(None) if i < frames_len && recovered.is_none() => {
+                                return Some(Err(Error::Decode(codec::DecodeError::InvalidData(
+                                    None,
+                                    format!("Decoded fewer frames than the {} counted", frames_len),
+                                ))));
+                            }
+                            Ok(None) => {
+                                (l, i) = (l + 1, 0);
+                                if l < self.loop_count {
+                                    report = color::quantize::QuantizeReport::worst(
+                                        report,
+                                        decoder.quantize_report(),
+                                    );
+                                    decoder = match open() {
+                                        Ok(decoder) => decoder,
+                                        Err(e) => return Some(Err(e)),
+                                    };
+                                }
+                                continue;
+                            }
+                            Err(e) => return Some(Err(e)),
+                        };
+
+                        let index = i + (frames_len * l);
+                        i += 1;
+                        if index >= colors.len() {
+                            return Some(Err(Error::Decode(codec::DecodeError::InvalidData(
+                                None,
+                                format!("Decoded more frames than the {} counted", frames_len),
+                            ))));
+                        }
+
+                        return Some(Ok((frame, index)));
+                    }
+
+                    return None;
+                });
+
+                // quantizing is what takes the longest for inputs that aren't indexed, so it's done
+                // by the workers along with the recoloring
+                return map_ordered(
+                    self.threads,
+                    frames,
+                    |(frame, index): (codec::PendingFrame<C>, usize)| {
+                        let (mut frame, frame_report) = frame.finish()?;
+                        recolor(&mut frame, &colors[index]);
+                        frame.delay = self.timing.delay(frame.delay, index, colors.len());
+                        return Ok((frame, frame_report));
+                    },
+                    |result: Result<_, Error>| {
+                        let (frame, frame_report) = result?;
+                        finished_report =
+                            color::quantize::QuantizeReport::worst(finished_report, frame_report);
+                        return encoder.encode(frame);
+                    },
+                );
+            };
+
+        match self.output_format {
+            codec::OutputFormat::Gif => {
+                let mut encoder = if self.global_palette {
+                    codec::gif::GifEncoder::with_global_palette(w, dimensions)?
+                } else {
+                    codec::gif::GifEncoder::new(w, dimensions)?
+                };
+                encoder.set_optimize(self.optimize);
+                encoder.set_transparency(transparency);
+                encoder.set_metadata(metadata);
+                write_frames(&encoder)?;
+                encoder.into_inner()?;
+            }
+
+            codec::OutputFormat::Apng => {
+                let encoder =
+                    codec::apng::ApngEncoder::new(w, dimensions, colors.len(), &metadata)?;
+                write_frames(&encoder)?;
+                encoder.finish()?;
+            }
+
+            codec::OutputFormat::Webp => {
+                let mut encoder = codec::webp::WebpEncoder::new(w, dimensions)?;
+                encoder.set_metadata(metadata);
+                write_frames(&encoder)?;
+                encoder.finish()?;
+            }
+        }
+
+        let report = color::quantize::QuantizeReport::worst(report, decoder.quantize_report());
+        return Ok(Report {
+            quantize: color::quantize::QuantizeReport::worst(report, finished_report),
+            recovered,
+        });
+    }
+
+    fn mix_none<C, W>(&self, w: W) -> Result<Report, Error>
+    where
+        C: color::Color,
+        W: io::Write,
+        palette::rgb::Rgb<color::EncodingType, color::ScalarType>:
+            palette::convert::FromColorUnclamped<
+                <C as palette::WithAlpha<color::ScalarType>>::Color,
+            >,
+    {
+        return self.mix_impl(w, |a: &C, _: &C| {
+            return a.clone();
+        });
+    }
+
+    fn mix_custom<H, C, W>(&self, w: W) -> Result<Report, Error>
+    where
+        C: color::Color
+            + color::Componentize<H, color::ScalarType, color::ScalarType, color::ScalarType>,
+        W: io::Write,
+        palette::rgb::Rgb<color::EncodingType, color::ScalarType>:
+            palette::convert::FromColorUnclamped<
+                <C as palette::WithAlpha<color::ScalarType>>::Color,
+            >,
+    {
+        return self.mix_impl(w, |a, b| {
+            return color::blend_colors::<
+                H,
+                color::ScalarType,
+                color::ScalarType,
+                color::ScalarType,
+                C,
+            >(a, b, true);
+        });
+    }
+
+    fn mix_lab<W: io::Write>(&self, w: W) -> Result<Report, Error> {
+        return self.mix_impl(
+            w,
+            |a: &palette::Laba<color::WhitePoint, color::ScalarType>,
+             b: &palette::Laba<color::WhitePoint, color::ScalarType>| {
+                return palette::Laba::from_components((a.l, b.a, b.b, a.alpha));
+            },
+        );
+    }
+
+    fn mix_oklab<W: io::Write>(&self, w: W) -> Result<Report, Error> {
+        return self.mix_impl(
+            w,
+            |a: &palette::Oklaba<color::ScalarType>, b: &palette::Oklaba<color::ScalarType>| {
+                return palette::Oklaba::from_components((a.l, b.a, b.b, a.alpha));
+            },
+        );
+    }
+
+    fn mix_linear<C, W>(&self, w: W) -> Result<Report, Error>
+    where
+        C: color::Color,
+        W: io::Write,
+        palette::rgb::Rgb<color::EncodingType, color::ScalarType>:
+            palette::convert::FromColorUnclamped<
+                <C as palette::WithAlpha<color::ScalarType>>::Color,
+            >,
+    {
+        // this isn't quite right, but again linear mixing might just not be ever
+        return self.mix_impl(w, |a: &C, b: &C| {
+            let (_, a_alpha) = a.clone().split();
+            if a_alpha <= 0.5 {
+                return a.clone();
+            }
+
+            return a.mix(b, 0.2);
+        });
+    }
+
+    fn mix_blend<C, W>(&self, w: W, blend_mode: color::blend::BlendMode) -> Result<Report, Error>
+    where
+        C: color::Color,
+        W: io::Write,
+        palette::rgb::Rgb<color::EncodingType, color::ScalarType>:
+            palette::convert::FromColorUnclamped<
+                <C as palette::WithAlpha<color::ScalarType>>::Color,
+            >,
+    {
+        return self.mix_impl(w, move |a: &C, b: &C| {
+            return color::blend::blend(a, b, blend_mode);
+        });
+    }
+}
+
+// Runs work on the items across a number of threads and passes the results on to sink in the order
+// the items came in. Only a few items are in flight at a time, so streamed frames stay streamed.
+fn map_ordered<T, U, I, F, S>(
+    threads: usize,
+    mut items: I,
+    work: F,
+    mut sink: S,
+) -> Result<(), Error>
+where
+    I: Iterator<Item = Result<T, Error>>,
+    T: Send,
+    U: Send,
+    F: Fn(T) -> U + Sync,
+    S: FnMut(U) -> Result<(), Error>,
+{
+    if threads <= 1 {
+        for item in items {
+            sink(work(item?))?;
+        }
+
+        return Ok(());
+    }
+
+    let (job_sender, job_receiver) = sync::mpsc::channel::<(usize, T)>();
+    let job_receiver = sync::Mutex::new(job_receiver);
+    let (result_sender, result_receiver) = sync::mpsc::channel::<(usize, thread::Result<U>)>();
+
+    return thread::scope(|scope| {
+        // owned by the scope so an early return drops it, which lets the workers finish
+        let job_sender = job_sender;
+        for _ in 0..threads {
+            let (job_receiver, result_sender, work) = (&job_receiver, result_sender.clone(), &work);
+            scope.spawn(move || loop {
+                // the lock is only held while waiting for the next job, and the jobs run out once
+                // the sender is dropped
+                let Ok((index, item)) = job_receiver.lock().unwrap().recv() else {
+                    return;
+                };
+
+                // a panic is sent back like a result, since the other workers would otherwise keep
+                // waiting for jobs and the results would never run out
+                let result = panic::catch_unwind(panic::AssertUnwindSafe(|| return work(item)));
+                if result_sender.send((index, result)).is_err() {
+                    return;
+                }
+            });
+        }
+        drop(result_sender);
+
+        let mut pending = collections::BTreeMap::new();
+        let (mut sent, mut next, mut done) = (0usize, 0usize, false);
+        loop {
+            // keeps the workers busy without getting too far ahead of the sink
+            while !done && sent - next < threads * 2 {
+                match items.next() {
+                    Some(Ok(item)) => {
+                        job_sender
+                            .send((sent, item))
+                            .expect("workers only stop once the jobs run out");
+                        sent += 1;
+                    }
+                    Some(Err(e)) => return Err(e),
+                    None => done = true,
+                }
+            }
+
+            if next == sent {
+                return Ok(());
+            }
+
+            let (index, result) = result_receiver
+                .recv()
+                .expect("workers only stop once the jobs run out");
+            // unwinding drops the job sender, so the workers finish before the scope panics again
+            pending.insert(index, result.unwrap_or_else(|e| panic::resume_unwind(e)));
+            while let Some(result) = pending.remove(&next) {
+                sink(result)?;
+                next += 1;
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{map_ordered, Rainbowify, Timing};
+    use crate::codec;
+    use crate::color;
+
+    const FIDGET_SPINNER: &[u8] = include_bytes!("../images/fidget_spinner.gif");
+
+    #[test]
+    fn test_encode_loops() {
+        let output = Rainbowify::from_reader(FIDGET_SPINNER)
+            .unwrap()
+            .mixing_mode(color::MixingMode::BlendColor)
+            .loop_count(2)
+            .encode()
+            .unwrap();
+
+        assert_eq!(
+            codec::gif::count_frames(&output[..]).unwrap(),
+            codec::gif::count_frames(FIDGET_SPINNER).unwrap() * 2
+        );
+    }
+
+    #[test]
+    fn test_trailing_metadata() {
+        // a comment after the last frame, well past what the decoder has read when it's opened
+        let mut input = FIDGET_SPINNER[..FIDGET_SPINNER.len() - 1].to_vec();
+        input.extend_from_slice(&[0x21, 0xFE, 8]);
+        input.extend_from_slice(b"trailing");
+        input.extend_from_slice(&[0, 0x3B]);
+
+        let output = Rainbowify::from_bytes(input)
+            .comment("added".to_owned())
+            .encode()
+            .unwrap();
+        assert_eq!(
+            codec::gif::read_metadata(&output[..]).comments,
+            ["trailing", "added"]
+        );
+    }
+
+    #[test]
+    fn test_detects_still_input() {
+        let input = codec::encode_test_image(
+            image::RgbaImage::from_pixel(4, 4, image::Rgba([255, 0, 0, 255])),
+            image::ImageFormat::Bmp,
+        );
+
+        assert_eq!(
+            codec::InputFormat::sniff(&input[..]).unwrap(),
+            codec::InputFormat::Still(image::ImageFormat::Bmp)
+        );
+
+        let output = Rainbowify::from_bytes(input)
+            .loop_count(3)
+            .encode()
+            .unwrap();
+        assert_eq!(codec::gif::count_frames(&output[..]).unwrap(), 3);
+    }
+
+    #[test]
+    fn test_static_override() {
+        let output = Rainbowify::from_reader(FIDGET_SPINNER)
+            .unwrap()
+            .is_static(true)
+            .loop_count(2)
+            .encode()
+            .unwrap();
+
+        assert_eq!(codec::gif::count_frames(&output[..]).unwrap(), 2);
+    }
+
+    fn delays(data: &[u8]) -> Vec<u16> {
+        let mut decoder = codec::gif::GifDecoder::<
+            _,
+            palette::Lcha<color::WhitePoint, color::ScalarType>,
+        >::new(data)
+        .unwrap();
+        return codec::Decodable::decode_all(&mut decoder)
+            .unwrap()
+            .unwrap()
+            .iter()
+            .map(|frame| frame.delay)
+            .collect();
+    }
+
+    #[test]
+    fn test_timing() {
+        let output = Rainbowify::from_reader(FIDGET_SPINNER)
+            .unwrap()
+            .is_static(true)
+            .loop_count(3)
+            .timing(Timing::Duration(100))
+            .encode()
+            .unwrap();
+        assert_eq!(delays(&output[..]), vec![33, 33, 34]);
+
+        let output = Rainbowify::from_reader(FIDGET_SPINNER)
+            .unwrap()
+            .timing(Timing::Speed(100.))
+            .encode()
+            .unwrap();
+        assert!(delays(&output[..])
+            .iter()
+            .all(|&delay| delay == super::MIN_DELAY));
+
+        assert!(Rainbowify::from_reader(FIDGET_SPINNER)
+            .unwrap()
+            .timing(Timing::Speed(0.))
+            .encode()
+            .is_err());
+
+        // still images have no delay of their own, while the input's are only raised to the minimum
+        let output = Rainbowify::from_reader(&include_bytes!("../images/chefs_kiss.png")[..])
+            .unwrap()
+            .loop_count(2)
+            .encode()
+            .unwrap();
+        assert!(delays(&output[..])
+            .iter()
+            .all(|&delay| delay == super::STILL_DELAY));
+        assert_eq!(Timing::Keep.delay(0, 0, 1), super::MIN_DELAY);
+        assert_eq!(Timing::Keep.delay(1, 0, 1), super::MIN_DELAY);
+        assert_eq!(Timing::Keep.delay(5, 0, 1), 5);
+    }
+
+    #[test]
+    fn test_threads() {
+        let encode = |threads| {
+            return Rainbowify::from_reader(FIDGET_SPINNER)
+                .unwrap()
+                .loop_count(2)
+                .threads(threads)
+                .encode()
+                .unwrap();
+        };
+
+        assert_eq!(encode(1), encode(4));
+
+        // frames that aren't indexed get quantized and transformed by the workers as well
+        let apng = Rainbowify::from_reader(FIDGET_SPINNER)
+            .unwrap()
+            .output_format(codec::OutputFormat::Apng)
+            .encode()
+            .unwrap();
+        let encode = |threads| {
+            return Rainbowify::from_bytes(apng.clone())
+                .resize(Some((32, 32)))
+                .threads(threads)
+                .encode_with_report()
+                .unwrap();
+        };
+        let ((single, single_report), (pooled, pooled_report)) = (encode(1), encode(4));
+        assert_eq!(single, pooled);
+        assert!(pooled_report.quantize.is_some());
+        assert_eq!(single_report.quantize, pooled_report.quantize);
+    }
+
+    #[test]
+    fn test_map_ordered() {
+        let mut output = Vec::new();
+        map_ordered(
+            3,
+            (0..100u64).map(Ok),
+            |i| {
+                // later items finish first
+                std::thread::sleep(std::time::Duration::from_micros((100 - i) * 10));
+                return i * 2;
+            },
+            |i| {
+                output.push(i);
+                return Ok(());
+            },
+        )
+        .unwrap();
+        assert_eq!(output, (0..100).map(|i| i * 2).collect::<Vec<_>>());
+
+        // errors from either end stop the work without hanging
+        let items = (0..100).map(|i| match i {
+            50 => Err(std::io::Error::other("bad item").into()),
+            i => Ok(i),
+        });
+        assert!(map_ordered(3, items, |i| i, |_| Ok(())).is_err());
+        let result = map_ordered(
+            3,
+            (0..100).map(Ok),
+            |i| i,
+            |i| match i {
+                10 => Err(std::io::Error::other("bad result").into()),
+                _ => Ok(()),
+            },
+        );
+        assert!(result.is_err());
+
+        // and so do panics in the work, which come back out of the calling thread
+        let result = std::panic::catch_unwind(|| {
+            return map_ordered(
+                3,
+                (0..100).map(Ok),
+                |i| {
+                    assert_ne!(i, 10, "bad work");
+                    return i;
+                },
+                |_| Ok(()),
+            );
+        });
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_lenient() {
+        let truncated = &FIDGET_SPINNER[..FIDGET_SPINNER.len() / 2];
+        assert!(Rainbowify::from_reader(truncated)
+            .unwrap()
+            .encode()
+            .is_err());
+
+        let (output, report) = Rainbowify::from_reader(truncated)
+            .unwrap()
+            .lenient(true)
+            .loop_count(2)
+            .encode_with_report()
+            .unwrap();
+        let (frames, _) = report.recovered.unwrap();
+        assert!(frames > 0);
+        assert_eq!(codec::gif::count_frames(&output[..]).unwrap(), frames * 2);
+
+        // an intact input is left alone
+        let (output, report) = Rainbowify::from_reader(FIDGET_SPINNER)
+            .unwrap()
+            .lenient(true)
+            .encode_with_report()
+            .unwrap();
+        assert!(report.recovered.is_none());
+        assert_eq!(
+            codec::gif::count_frames(&output[..]).unwrap(),
+            codec::gif::count_frames(FIDGET_SPINNER).unwrap()
+        );
+    }
+
+    // the palette of every frame as the bytes in the file
+    fn gif_palettes(data: &[u8]) -> Vec<Vec<u8>> {
+        let mut decoder = gif::DecodeOptions::new().read_info(data).unwrap();
+        let global = decoder.global_palette().map(|p| p.to_vec());
+        let mut palettes = Vec::new();
+        while let Some(frame) = decoder.read_next_frame().unwrap() {
+            palettes.push(frame.palette.clone().or(global.clone()).unwrap());
+        }
+
+        return palettes;
+    }
+
+    #[test]
+    fn test_none_keeps_palettes() {
+        for color_space in [
+            color::ColorSpace::HSL,
+            color::ColorSpace::HSV,
+            color::ColorSpace::LAB,
+            color::ColorSpace::LCH,
+            color::ColorSpace::OKLAB,
+            color::ColorSpace::OKLCH,
+        ] {
+            let output = Rainbowify::from_reader(FIDGET_SPINNER)
+                .unwrap()
+                .mixing_mode(color::MixingMode::None)
+                .color_space(color_space)
+                .verify_palette(true)
+                .encode()
+                .unwrap();
+
+            assert_eq!(gif_palettes(&output[..]), gif_palettes(FIDGET_SPINNER));
+        }
+    }
+
+    #[test]
+    fn test_transparency() {
+        // GIFs can't store partial alpha, so it's flattened even when asked to be kept
+        let input = codec::encode_test_image(
+            image::RgbaImage::from_fn(16, 16, |x, _| image::Rgba([255, 255, 255, (x * 17) as u8])),
+            image::ImageFormat::Png,
+        );
+        let output = Rainbowify::from_bytes(input)
+            .mixing_mode(color::MixingMode::None)
+            .preserve_alpha(true)
+            .encode()
+            .unwrap();
+
+        let mut options = gif::DecodeOptions::new();
+        options.set_color_output(gif::ColorOutput::RGBA);
+        let mut decoder = options.read_info(&output[..]).unwrap();
+        let rgba = decoder.read_next_frame().unwrap().unwrap().buffer.to_vec();
+        assert!(rgba.chunks(4).all(|pixel| pixel[3] == 0 || pixel[3] == 255));
+        assert!(rgba.chunks(4).any(|pixel| pixel[3] == 255));
+    }
+}